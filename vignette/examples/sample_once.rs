@@ -82,8 +82,7 @@ fn main() {
 
     println!("Done sampling");
 
-    let mut outputter = Outputter::new();
-    let output_profile = outputter.output(session.finish());
+    let output_profile = Outputter::new().output(session.finish());
     let filename = format!("{}.vignette", process::id());
     let file = File::create(&filename).unwrap();
     serde_json::to_writer_pretty(file, &output_profile).unwrap();
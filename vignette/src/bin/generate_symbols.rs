@@ -1,16 +1,21 @@
 // Generates a symbol file in a given location, assuming dump_syms is in the path.
+//
+// The symbols are stored under the debug ID reported by dump_syms, like in a breakpad symbol
+// store, unless an identifier is passed as the third argument. Use that for modules that vignette
+// identifies by their debuglink CRC or modification time and size.
 
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Cursor;
 use std::io::Write;
-use std::path::{PathBuf};
+use std::path::PathBuf;
 
 fn main() {
     let mut args = std::env::args();
     args.next().expect("the program itself");
     let binary = args.next().expect("binary");
     let symbol_root = args.next().expect("symbols location");
+    let id = args.next();
 
     let mut dump_syms = std::process::Command::new("dump_syms");
     dump_syms.arg(binary);
@@ -31,13 +36,14 @@ fn main() {
     // MODULE Linux x86_64 1428AABFDBD2A52E08B6D967319FB6FE0 sample_once
     assert!(header[0].starts_with("MODULE"));
     let binary_name = header[0].split(" ").nth(4).expect("binary name");
-    // INFO CODE_ID BFAA2814D2DB2EA508B6D967319FB6FEF5B14C2C
-    assert!(header[1].starts_with("INFO CODE_ID"));
-    let build_id = header[1].split(" ").nth(2).expect("build id");
+    let debug_id = match id {
+        Some(ref id) => id.as_str(),
+        None => header[0].split(" ").nth(3).expect("debug id"),
+    };
 
     let mut sym_path = PathBuf::from(symbol_root);
     sym_path.push(binary_name);
-    sym_path.push(build_id);
+    sym_path.push(debug_id);
 
     std::fs::create_dir_all(&sym_path).expect("created dirs");
     sym_path.push(format!("{}.sym", binary_name));
@@ -12,16 +12,35 @@ fn main() {
         .read(true)
         .open(resolved_profile_path)
         .expect("file");
-    let resolved_profile: output::ResolvedProfile = serde_json::from_reader(file).unwrap();
+    let resolved_profile = output::load_resolved(file).expect("valid profile");
 
-    let speed_frames: Vec<speedscope::Frame> = resolved_profile
+    // Speedscope has no notion of inlining, so every inlined call becomes a frame of its own,
+    // marked in its name. `expanded` maps each resolved frame to its speedscope frames, innermost
+    // first like the samples.
+    let mut speed_frames: Vec<speedscope::Frame> = Vec::new();
+    let expanded: Vec<Vec<usize>> = resolved_profile
         .frames
         .iter()
-        .map(|frame| speedscope::Frame {
-            name: frame.name.clone(),
-            file: Some(frame.file.clone()),
-            line: Some(frame.line),
-            col: None,
+        .map(|frame| {
+            let inlined = frame.inlined.iter().map(|inlined| speedscope::Frame {
+                name: format!("{} (inlined)", inlined.name),
+                file: Some(inlined.file.clone()),
+                line: Some(inlined.line),
+                col: None,
+            });
+            let outermost = speedscope::Frame {
+                name: frame.name.clone(),
+                file: Some(frame.file.clone()),
+                line: Some(frame.line),
+                col: None,
+            };
+            inlined
+                .chain(std::iter::once(outermost))
+                .map(|speed_frame| {
+                    speed_frames.push(speed_frame);
+                    speed_frames.len() - 1
+                })
+                .collect()
         })
         .collect();
 
@@ -32,9 +51,14 @@ fn main() {
             let samples: Vec<Vec<usize>> = thread
                 .samples
                 .into_iter()
-                .map(|mut sample| {
-                    sample.frames.reverse();
-                    sample.frames
+                .map(|sample| {
+                    let mut frames: Vec<usize> = sample
+                        .frames
+                        .iter()
+                        .flat_map(|&frame| expanded[frame].iter().cloned())
+                        .collect();
+                    frames.reverse();
+                    frames
                 })
                 .collect();
             (Some(thread.thread_id), samples)
@@ -0,0 +1,38 @@
+// Serve symbolication requests over HTTP, keeping symbols loaded between them. See
+// vignette::symbolize::server for the endpoints.
+extern crate vignette;
+
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::sync::Arc;
+use vignette::symbolize::{server, Symbolizer};
+
+// Modules whose symbols are kept loaded. Symbols of large libraries take hundreds of megabytes.
+const MEMORY_CACHE_MODULES: usize = 64;
+
+fn main() {
+    let mut args = std::env::args();
+    args.next().expect("the program itself");
+    let address = args
+        .next()
+        .expect("address to listen on, e.g. 127.0.0.1:8000");
+    // The same symbol locations as resolve takes.
+    let (servers, search_roots): (Vec<String>, Vec<String>) =
+        args.partition(|arg| arg.contains("://"));
+
+    let (mut symbolizer, warnings) = Symbolizer::with_standard_sources(
+        search_roots.into_iter().map(PathBuf::from).collect(),
+        servers,
+    );
+    for warning in &warnings {
+        eprintln!("warning: {}", warning);
+    }
+    symbolizer.set_memory_cache(MEMORY_CACHE_MODULES);
+
+    let listener = TcpListener::bind(&address).expect("listening socket");
+    eprintln!(
+        "listening on {}",
+        listener.local_addr().expect("bound address")
+    );
+    server::serve(listener, Arc::new(symbolizer)).expect("accepted connections");
+}
@@ -0,0 +1,177 @@
+// Client for debuginfod servers, which serve debug files and executables by build ID.
+//
+// Configured like elfutils' client, through DEBUGINFOD_URLS, DEBUGINFOD_CACHE_PATH and
+// DEBUGINFOD_TIMEOUT, and keeping the same cache layout, so the two can share a cache.
+
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use download::{self, Options};
+
+// elfutils' default, which is generous since debug files can be large.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(90);
+
+// How long a file that no server had is not asked for again, like elfutils' default cache_miss_s.
+const MISSING_TTL: Duration = Duration::from_secs(600);
+
+// The largest file we download. Debug files of large binaries run into gigabytes.
+const MAX_SIZE: u64 = 4 << 30;
+
+/// Fetches files from debuginfod servers, and keeps them in a cache on disk.
+#[derive(Debug, Clone)]
+pub struct Client {
+    urls: Vec<String>,
+    cache_dir: PathBuf,
+    timeout: Duration,
+}
+
+impl Client {
+    /// Creates a client for the servers in `urls`, which are tried in order, caching files under
+    /// `cache_dir`.
+    pub fn new(urls: Vec<String>, cache_dir: PathBuf) -> Self {
+        Client {
+            urls,
+            cache_dir,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Creates a client for the servers in DEBUGINFOD_URLS, or None if there are none.
+    ///
+    /// Only `http://` servers can be asked. The URLs of others are returned too, for the caller to
+    /// warn about.
+    pub fn from_env() -> (Option<Self>, Vec<String>) {
+        let (urls, skipped): (Vec<String>, Vec<String>) = env::var("DEBUGINFOD_URLS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(|url| url.trim_end_matches('/').to_owned())
+            .partition(|url| url.starts_with("http://"));
+        if urls.is_empty() {
+            return (None, skipped);
+        }
+        let cache_dir = match env::var_os("DEBUGINFOD_CACHE_PATH") {
+            Some(path) => PathBuf::from(path),
+            None => match env::var_os("XDG_CACHE_HOME") {
+                Some(path) => PathBuf::from(path).join("debuginfod_client"),
+                None => match env::var_os("HOME") {
+                    Some(home) => PathBuf::from(home).join(".cache").join("debuginfod_client"),
+                    None => return (None, skipped),
+                },
+            },
+        };
+        let mut client = Client::new(urls, cache_dir);
+        if let Some(timeout) = env::var("DEBUGINFOD_TIMEOUT")
+            .ok()
+            .and_then(|timeout| timeout.parse().ok())
+        {
+            client.timeout = Duration::from_secs(timeout);
+        }
+        (Some(client), skipped)
+    }
+
+    /// Returns the path of the separate debug file for `build_id`, given in hex, downloading it
+    /// if it is not cached yet. Returns None if no server has it, and an error if a server could
+    /// not be asked and none of the others had it.
+    pub fn debuginfo(&self, build_id: &str) -> io::Result<Option<PathBuf>> {
+        self.fetch(build_id, "debuginfo")
+    }
+
+    /// Like `debuginfo`, but for the executable or shared library itself.
+    pub fn executable(&self, build_id: &str) -> io::Result<Option<PathBuf>> {
+        self.fetch(build_id, "executable")
+    }
+
+    fn fetch(&self, build_id: &str, kind: &str) -> io::Result<Option<PathBuf>> {
+        // The build ID ends up in a path, so it must not be able to escape the cache.
+        if build_id.is_empty() || !build_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid build ID {}", build_id),
+            ));
+        }
+        let build_id = build_id.to_lowercase();
+        let urls: Vec<String> = self
+            .urls
+            .iter()
+            .map(|url| format!("{}/buildid/{}/{}", url, build_id, kind))
+            .collect();
+        let options = Options {
+            timeout: self.timeout,
+            max_size: MAX_SIZE,
+            missing_ttl: MISSING_TTL,
+        };
+        download::fetch(self.cache_dir.join(&build_id).join(kind), &urls, &options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use download::fixtures::{cache_dir, serve};
+    use std::fs;
+    use std::sync::atomic::Ordering;
+
+    const BUILD_ID: &str = "0123456789abcdef";
+    const BROKEN_ID: &str = "fedcba9876543210";
+
+    // Serves the debuginfo of BUILD_ID, fails for BROKEN_ID, and 404s everything else.
+    fn respond(request: &str) -> String {
+        if request.starts_with(&format!("GET /buildid/{}/debuginfo ", BUILD_ID)) {
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nDWARF".to_owned()
+        } else if request.starts_with(&format!("GET /buildid/{}/", BROKEN_ID)) {
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_owned()
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned()
+        }
+    }
+
+    #[test]
+    fn test_fetch_and_cache() {
+        let (url, requests) = serve(respond);
+        let cache_dir = cache_dir("debuginfod-fetch");
+        let client = Client::new(vec![url], cache_dir.clone());
+
+        let path = client.debuginfo(&BUILD_ID.to_uppercase()).unwrap().unwrap();
+        assert_eq!(path, cache_dir.join(BUILD_ID).join("debuginfo"));
+        assert_eq!(fs::read(&path).unwrap(), b"DWARF");
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Served from the cache this time.
+        assert_eq!(client.debuginfo(BUILD_ID).unwrap(), Some(path));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Missing files are only asked for once.
+        assert_eq!(client.executable(BUILD_ID).unwrap(), None);
+        assert_eq!(client.executable(BUILD_ID).unwrap(), None);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Failures are reported, and asked again.
+        assert!(client.debuginfo(BROKEN_ID).is_err());
+        assert!(client.debuginfo(BROKEN_ID).is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        assert!(!cache_dir.join(BROKEN_ID).exists());
+
+        assert!(client.debuginfo("../etc").is_err());
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_fallback_servers() {
+        let (url, _) = serve(respond);
+        let cache_dir = cache_dir("debuginfod-fallback");
+        // Nothing listens on port 1.
+        let client = Client::new(
+            vec!["http://127.0.0.1:1".to_owned(), url],
+            cache_dir.clone(),
+        );
+        assert!(client.debuginfo(BUILD_ID).unwrap().is_some());
+        // The unreachable server might have had it.
+        assert!(client.debuginfo("abcd").is_err());
+
+        let unreachable = Client::new(vec!["http://127.0.0.1:1".to_owned()], cache_dir.clone());
+        assert!(unreachable.debuginfo("abcd").is_err());
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+// Downloads into a cache on disk, shared by the debuginfod and symbol server clients.
+//
+// Files are kept at the paths the clients choose. Files that no server has are remembered by an
+// empty `<file>.missing` marker next to where they would go, so that they are not asked for again
+// until the marker is older than the client's TTL.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use http;
+
+// Counts the downloads this process started, to give each partial file a name of its own.
+static DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// How to download files into the cache.
+pub struct Options {
+    pub timeout: Duration,
+    /// The largest file to download.
+    pub max_size: u64,
+    /// How long a file that no server had is not asked for again.
+    pub missing_ttl: Duration,
+}
+
+/// Returns `path` if it is cached, and otherwise downloads it from the first of `urls` that has
+/// it. Returns None if none has it, and an error if a server could not be asked and none of the
+/// others had it.
+pub fn fetch(path: PathBuf, urls: &[String], options: &Options) -> io::Result<Option<PathBuf>> {
+    if path.is_file() {
+        return Ok(Some(path));
+    }
+    let file_name = path
+        .file_name()
+        .expect("cached files have names")
+        .to_string_lossy()
+        .into_owned();
+    let missing = path.with_file_name(format!("{}.missing", file_name));
+    let recently_missing = fs::metadata(&missing)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+        .is_some_and(|age| age < options.missing_ttl);
+    if recently_missing {
+        return Ok(None);
+    }
+
+    let mut errors = Vec::new();
+    for url in urls {
+        let mut partial = Partial::new(path.with_file_name(format!(
+            ".{}.{}.{}",
+            file_name,
+            process::id(),
+            DOWNLOADS.fetch_add(1, Ordering::Relaxed)
+        )));
+        match http::get(url, options.timeout, options.max_size, &mut partial) {
+            Ok(200) => {
+                partial.finish(&path)?;
+                let _ = fs::remove_file(&missing);
+                return Ok(Some(path));
+            }
+            Ok(404) => {}
+            Ok(status) => errors.push(format!("{}: HTTP {}", url, status)),
+            Err(e) => errors.push(format!("{}: {}", url, e)),
+        }
+    }
+    if !errors.is_empty() {
+        // Not remembered as missing, since the server that failed may well have it.
+        return Err(io::Error::other(errors.join(", ")));
+    }
+    if let Some(dir) = missing.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&missing, b"")?;
+    Ok(None)
+}
+
+// A file being downloaded, written under a temporary name so that an interrupted download is
+// never mistaken for a complete one. It is only created once the body arrives, and removed unless
+// the download finishes.
+struct Partial {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Partial {
+    fn new(path: PathBuf) -> Self {
+        Partial { path, file: None }
+    }
+
+    fn file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            self.file = Some(File::create(&self.path)?);
+        }
+        Ok(self.file.as_mut().expect("created above"))
+    }
+
+    // Moves the complete file to `path`.
+    fn finish(mut self, path: &Path) -> io::Result<()> {
+        self.file()?;
+        fs::rename(&self.path, path)?;
+        self.file = None;
+        Ok(())
+    }
+}
+
+impl Write for Partial {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file()?.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file {
+            Some(ref mut file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// A server and cache directory for the clients' tests.
+#[cfg(test)]
+pub mod fixtures {
+    use std::env;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Serves the responses `respond` returns for request lines, e.g. "GET /path HTTP/1.1".
+    /// Returns the server's URL and the number of requests it received.
+    pub fn serve<F>(respond: F) -> (String, Arc<AtomicUsize>)
+    where
+        F: Fn(&str) -> String + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                // The request line, then the headers up to an empty line.
+                let mut reader = BufReader::new(&stream);
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                counter.fetch_add(1, Ordering::SeqCst);
+                stream.write_all(respond(&request).as_bytes()).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    /// Returns an empty directory for the test `name`.
+    pub fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("vignette-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+}
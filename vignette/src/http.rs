@@ -0,0 +1,409 @@
+// Just enough of an HTTP/1.1 client to download symbols.
+//
+// Symbol servers are usually reached over plain HTTP inside a build network, and pulling in a full
+// client with TLS would burden every application that only records profiles. Only `http://` URLs
+// are supported; anything else is reported as an error so that callers can move on to the next
+// server.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// Redirects to follow before giving up, e.g. from a debuginfod federation to the server that has
+// the file.
+const MAX_REDIRECTS: usize = 5;
+
+// Limits on the lines and headers of a message, so that a peer can't make us buffer without end.
+const MAX_LINE: u64 = 8 << 10;
+const MAX_HEADERS: usize = 100;
+
+struct Url<'a> {
+    // Host and port, as sent in the Host header.
+    authority: &'a str,
+    path: &'a str,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_url<'a>(url: &'a str) -> io::Result<Url<'a>> {
+    if !url.starts_with("http://") {
+        return Err(invalid(format!("unsupported URL {}", url)));
+    }
+    let rest = &url["http://".len()..];
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return Err(invalid(format!("URL without a host {}", url)));
+    }
+    Ok(Url { authority, path })
+}
+
+// Resolves the `location` a response to `url` redirected to, which may be relative to it.
+fn resolve_location(url: &Url, location: &str) -> String {
+    if location.contains("://") {
+        location.to_owned()
+    } else if location.starts_with("//") {
+        format!("http:{}", location)
+    } else if location.starts_with('/') {
+        format!("http://{}{}", url.authority, location)
+    } else {
+        // Relative to the directory of the path, without its query.
+        let path = url.path.split('?').next().unwrap_or("");
+        let dir = &path[..path.rfind('/').map_or(0, |slash| slash + 1)];
+        format!("http://{}{}{}", url.authority, dir, location)
+    }
+}
+
+// Connects to the host and port in `authority`, trying each of its addresses in turn.
+fn connect(authority: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let address = if authority.contains(':') {
+        authority.to_owned()
+    } else {
+        format!("{}:80", authority)
+    };
+    let mut error = None;
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap_or_else(|| invalid(format!("no addresses for {}", authority))))
+}
+
+/// A request received by a server.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The request target, e.g. "/symbolicate/v5".
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+// What we need from the headers of a message.
+#[derive(Default)]
+struct Headers {
+    content_length: Option<u64>,
+    chunked: bool,
+    location: Option<String>,
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_owned())
+}
+
+// Reads a line into `line`, replacing what it held. Returns 0 at the end of the input, and an
+// error for lines longer than MAX_LINE.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    line.clear();
+    let read = reader.by_ref().take(MAX_LINE).read_line(line)?;
+    if read as u64 == MAX_LINE && !line.ends_with('\n') {
+        return Err(malformed("line too long"));
+    }
+    Ok(read)
+}
+
+// Reads the headers, up to the empty line that ends them.
+fn read_headers<R: BufRead>(reader: &mut R) -> io::Result<Headers> {
+    let mut headers = Headers::default();
+    let mut line = String::new();
+    for _ in 0..=MAX_HEADERS {
+        if read_line(reader, &mut line)? == 0 {
+            return Err(malformed("truncated headers"));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(headers);
+        }
+        let colon = match header.find(':') {
+            Some(colon) => colon,
+            None => continue,
+        };
+        let (name, value) = (&header[..colon], header[colon + 1..].trim());
+        if name.eq_ignore_ascii_case("content-length") {
+            headers.content_length = Some(
+                value
+                    .parse::<u64>()
+                    .map_err(|_| malformed("malformed content length"))?,
+            );
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            headers.chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("location") {
+            headers.location = Some(value.to_owned());
+        }
+    }
+    Err(malformed("too many headers"))
+}
+
+// Copies a body of at most `limit` bytes to `body`. It may be sent with a length, chunked, or, if
+// `until_eof`, until the connection is closed. Requests without a length have no body.
+fn read_body<R: BufRead, W: Write>(
+    reader: &mut R,
+    headers: &Headers,
+    until_eof: bool,
+    limit: u64,
+    body: &mut W,
+) -> io::Result<()> {
+    let too_large = || malformed("body too large");
+    if headers.chunked {
+        let mut line = String::new();
+        let mut total = 0;
+        loop {
+            read_line(reader, &mut line)?;
+            // Chunk extensions follow a semicolon.
+            let size = line.trim_end().split(';').next().unwrap_or("");
+            let size =
+                u64::from_str_radix(size, 16).map_err(|_| malformed("malformed chunk size"))?;
+            if size == 0 {
+                break;
+            }
+            total += size;
+            if total > limit {
+                return Err(too_large());
+            }
+            if io::copy(&mut reader.take(size), body)? != size {
+                return Err(malformed("truncated chunk"));
+            }
+            read_line(reader, &mut line)?;
+        }
+    } else if let Some(length) = headers.content_length {
+        if length > limit {
+            return Err(too_large());
+        }
+        if io::copy(&mut reader.take(length), body)? != length {
+            return Err(malformed("truncated body"));
+        }
+    } else if until_eof && io::copy(&mut reader.take(limit.saturating_add(1)), body)? > limit {
+        return Err(too_large());
+    }
+    Ok(())
+}
+
+/// Reads a response with a body of at most `max_body` bytes, and returns its status and the
+/// location it redirects to, if any. The body is copied to `body` if the status is 200, and
+/// discarded otherwise.
+fn read_response<R: BufRead, W: Write>(
+    mut reader: R,
+    max_body: u64,
+    body: &mut W,
+) -> io::Result<(u16, Option<String>)> {
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    // e.g. "HTTP/1.1 200 OK"
+    let status = line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| malformed("malformed status line"))?;
+    let headers = read_headers(&mut reader)?;
+    if status == 200 {
+        read_body(&mut reader, &headers, true, max_body, body)?;
+    } else {
+        read_body(&mut reader, &headers, true, max_body, &mut io::sink())?;
+    }
+    Ok((status, headers.location))
+}
+
+/// Reads a request with a body of at most `max_body` bytes.
+pub fn read_request<R: BufRead>(mut reader: R, max_body: u64) -> io::Result<Request> {
+    let mut line = String::new();
+    read_line(&mut reader, &mut line)?;
+    // e.g. "POST /symbolicate/v5 HTTP/1.1"
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/") => {
+            (method.to_owned(), path.to_owned())
+        }
+        _ => return Err(malformed("malformed request line")),
+    };
+    let headers = read_headers(&mut reader)?;
+    let mut body = Vec::new();
+    read_body(&mut reader, &headers, false, max_body, &mut body)?;
+    Ok(Request { method, path, body })
+}
+
+/// Writes a complete response, after which the connection is closed.
+pub fn write_response<W: Write>(
+    mut writer: W,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    )?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+/// Fetches `url`, following redirects, and returns the status of the response that is not a
+/// redirect. Its body is copied to `body` as it arrives if the status is 200, and discarded
+/// otherwise. Bodies larger than `max_body` bytes are an error, after which `body` holds part of
+/// one.
+pub fn get<W: Write>(url: &str, timeout: Duration, max_body: u64, body: &mut W) -> io::Result<u16> {
+    let mut url = url.to_owned();
+    for _ in 0..=MAX_REDIRECTS {
+        let (status, location) = {
+            let parsed = parse_url(&url)?;
+            let mut stream = connect(parsed.authority, timeout)?;
+            stream.set_read_timeout(Some(timeout))?;
+            stream.set_write_timeout(Some(timeout))?;
+            // In one write, since servers may answer and close the connection as soon as they have
+            // the request line.
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                parsed.path, parsed.authority
+            );
+            stream.write_all(request.as_bytes())?;
+            let (status, location) = read_response(BufReader::new(stream), max_body, body)?;
+            (
+                status,
+                location.map(|location| resolve_location(&parsed, &location)),
+            )
+        };
+        match (status, location) {
+            (301, Some(location))
+            | (302, Some(location))
+            | (303, Some(location))
+            | (307, Some(location))
+            | (308, Some(location)) => url = location,
+            _ => return Ok(status),
+        }
+    }
+    Err(io::Error::other(format!(
+        "too many redirects fetching {}",
+        url
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        let url = parse_url("http://symbols.example:8002/buildid/ab/debuginfo").unwrap();
+        assert_eq!(url.authority, "symbols.example:8002");
+        assert_eq!(url.path, "/buildid/ab/debuginfo");
+        assert_eq!(parse_url("http://symbols.example").unwrap().path, "/");
+        assert!(parse_url("https://symbols.example/").is_err());
+        assert!(parse_url("http:///path").is_err());
+    }
+
+    #[test]
+    fn test_resolve_location() {
+        let url = parse_url("http://a:8002/buildid/ab/debuginfo?x=/y").unwrap();
+        assert_eq!(resolve_location(&url, "http://b/x"), "http://b/x");
+        assert_eq!(resolve_location(&url, "//b/x"), "http://b/x");
+        assert_eq!(resolve_location(&url, "/x"), "http://a:8002/x");
+        assert_eq!(
+            resolve_location(&url, "executable"),
+            "http://a:8002/buildid/ab/executable"
+        );
+    }
+
+    #[test]
+    fn test_read_response() {
+        // Returns the status, location and body of `response`.
+        fn read(response: &[u8], max_body: u64) -> io::Result<(u16, Option<String>, Vec<u8>)> {
+            let mut body = Vec::new();
+            let (status, location) = read_response(response, max_body, &mut body)?;
+            Ok((status, location, body))
+        }
+
+        let (status, _, body) = read(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello, extra",
+            10,
+        )
+        .unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body, b"hello");
+
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        3\r\nhel\r\n2;ext=1\r\nlo\r\n0\r\n\r\n";
+        assert_eq!(read(chunked, 10).unwrap().2, b"hello");
+        assert!(read(chunked, 4).is_err());
+
+        let (status, location, _) =
+            read(b"HTTP/1.0 302 Found\r\nLocation: http://b/x\r\n\r\n", 10).unwrap();
+        assert_eq!(status, 302);
+        assert_eq!(location.as_deref(), Some("http://b/x"));
+
+        // Only successful responses have their bodies kept.
+        let (status, _, body) = read(
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope",
+            10,
+        )
+        .unwrap();
+        assert_eq!(status, 404);
+        assert!(body.is_empty());
+
+        assert!(read(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nshort", 10).is_err());
+        assert!(read(b"garbage\r\n\r\n", 10).is_err());
+        // Bodies until the end of the connection are limited too.
+        assert!(read(b"HTTP/1.0 200 OK\r\n\r\n0123456789a", 10).is_err());
+    }
+
+    #[test]
+    fn test_read_request() {
+        let request = read_request(
+            &b"POST /symbolicate/v5 HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}"[..],
+            10,
+        )
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/symbolicate/v5");
+        assert_eq!(request.body, b"{}");
+
+        // Without a length, a request has no body.
+        let request = read_request(&b"GET / HTTP/1.1\r\nHost: a\r\n\r\nextra"[..], 10).unwrap();
+        assert!(request.body.is_empty());
+
+        let large = b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n0123456789a";
+        assert!(read_request(&large[..], 10).is_err());
+
+        // Nor may the headers be endless.
+        let mut long_line = b"GET / HTTP/1.1\r\nX: ".to_vec();
+        long_line.resize(long_line.len() + MAX_LINE as usize, b'x');
+        long_line.extend_from_slice(b"\r\n\r\n");
+        assert!(read_request(&long_line[..], 10).is_err());
+        let mut many_headers = b"GET / HTTP/1.1\r\n".to_vec();
+        for _ in 0..=MAX_HEADERS {
+            many_headers.extend_from_slice(b"X: x\r\n");
+        }
+        many_headers.extend_from_slice(b"\r\n");
+        assert!(read_request(&many_headers[..], 10).is_err());
+        assert!(read_request(&b"garbage\r\n\r\n"[..], 10).is_err());
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut response = Vec::new();
+        write_response(&mut response, 400, "text/plain", b"nope").unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+        let mut body = Vec::new();
+        assert_eq!(read_response(&response[..], 10, &mut body).unwrap().0, 400);
+        // Not kept, since only successful responses are.
+        assert!(body.is_empty());
+        response.clear();
+        write_response(&mut response, 200, "text/plain", b"fine").unwrap();
+        assert_eq!(read_response(&response[..], 10, &mut body).unwrap().0, 200);
+        assert_eq!(body, b"fine");
+    }
+}
@@ -0,0 +1,517 @@
+// Symbols for code generated at runtime, which lives in anonymous mappings outside any module.
+//
+// JIT compilers can describe their code in three ways: by calling `register_jit_region`, by
+// appending to a perf map at /tmp/perf-<pid>.map, or by writing a jitdump file and mapping it into
+// memory, which is also how perf finds it. All of them are merged into a `JitMap`, which samples
+// hold on to like they do with module maps. No symbol files exist for this code, so frames in it
+// are attributed to a synthetic module that carries the symbol names inline.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use module_cache::{native_arch, IdKind, ModuleInfo};
+
+const JITDUMP_MAGIC: u32 = 0x4a69_5444;
+const JITDUMP_HEADER_SIZE: usize = 40;
+const JITDUMP_RECORD_HEADER_SIZE: usize = 16;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_MOVE: u32 = 1;
+// Looking for a jitdump means reading /proc/self/maps, so only do that this often until found.
+const JITDUMP_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A function generated at runtime.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct JitSymbol {
+    pub address: u64,
+    pub size: u64,
+    pub name: String,
+}
+
+// Symbols by start address. Never overlapping, see `insert`.
+type Symbols = BTreeMap<u64, JitSymbol>;
+
+/// Adds `symbol`, replacing any symbols it overlaps. JIT code is usually freed and its memory
+/// reused for other code, so the latest symbol for an address is the one that applies from now on.
+fn insert(symbols: &mut Symbols, symbol: JitSymbol) {
+    let end = symbol.address.saturating_add(symbol.size);
+    let overlapping: Vec<u64> = symbols
+        .range(..end)
+        .rev()
+        .take_while(|&(_, old)| old.address.saturating_add(old.size) > symbol.address)
+        .map(|(&address, _)| address)
+        .collect();
+    for address in overlapping {
+        symbols.remove(&address);
+    }
+    symbols.insert(symbol.address, symbol);
+}
+
+struct Registry {
+    // Incremented whenever `symbols` changes.
+    generation: u64,
+    symbols: Symbols,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    generation: 0,
+    symbols: BTreeMap::new(),
+});
+
+/// Names the JIT code at `start..start + len`, so that samples in it are attributed to `name`.
+///
+/// Registering a region that overlaps earlier ones replaces them.
+pub fn register_jit_region(start: usize, len: usize, name: &str) {
+    let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+    insert(
+        &mut registry.symbols,
+        JitSymbol {
+            address: start as u64,
+            size: len as u64,
+            name: name.to_owned(),
+        },
+    );
+    registry.generation += 1;
+}
+
+/// The module that frames in JIT code are attributed to.
+pub fn jit_module_info() -> ModuleInfo {
+    ModuleInfo {
+        name: "[jit]".to_owned(),
+        code_id: String::new(),
+        debug_id: String::new(),
+        id_kind: IdKind::Jit,
+        path: String::new(),
+        base_address: 0,
+        size: 0,
+        image_vaddr: 0,
+        arch: native_arch().to_owned(),
+        soname: None,
+        debug_file: None,
+    }
+}
+
+/// Parses a line of a perf map, which is the start address and size in hex, followed by the name.
+fn parse_perf_map_line(line: &str) -> Option<JitSymbol> {
+    let hex = |field: &str| {
+        let field = field.trim_start_matches("0x");
+        u64::from_str_radix(field, 16).ok()
+    };
+    let mut fields = line.trim_end().splitn(3, ' ');
+    let address = hex(fields.next()?)?;
+    let size = hex(fields.next()?)?;
+    let name = fields.next()?;
+    Some(JitSymbol {
+        address,
+        size,
+        name: name.to_owned(),
+    })
+}
+
+/// Reads a perf map as the JIT appends to it.
+struct PerfMap {
+    path: PathBuf,
+    reader: Option<BufReader<File>>,
+    // The incomplete last line, if the JIT was still writing it.
+    line: String,
+    symbols: Symbols,
+}
+
+impl PerfMap {
+    fn new(path: PathBuf) -> Self {
+        PerfMap {
+            path,
+            reader: None,
+            line: String::new(),
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    /// Reads lines appended since the last call, and returns whether there were any.
+    fn update(&mut self) -> bool {
+        if self.reader.is_none() {
+            match File::open(&self.path) {
+                Ok(file) => self.reader = Some(BufReader::new(file)),
+                Err(_) => return false,
+            }
+        }
+        let reader = self.reader.as_mut().unwrap();
+
+        let mut changed = false;
+        loop {
+            match reader.read_line(&mut self.line) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(_) => {
+                    // Not UTF-8. Skip the line.
+                    self.line.clear();
+                    continue;
+                }
+            }
+            // Finish the line on the next call.
+            if !self.line.ends_with('\n') {
+                break;
+            }
+            if let Some(symbol) = parse_perf_map_line(&self.line) {
+                insert(&mut self.symbols, symbol);
+                changed = true;
+            }
+            self.line.clear();
+        }
+        changed
+    }
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[at..at + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[at..at + 8]);
+    u64::from_ne_bytes(bytes)
+}
+
+/// Parses the complete jitdump records at the start of `data` into `symbols`, and returns the
+/// number of bytes consumed. `data` starts with the file header if `at_start` is set.
+///
+/// Returns None if `data` is not a jitdump written by this process' architecture.
+fn parse_jitdump(data: &[u8], at_start: bool, symbols: &mut Symbols) -> Option<usize> {
+    let mut offset = 0;
+    if at_start {
+        if data.len() < JITDUMP_HEADER_SIZE {
+            return Some(0);
+        }
+        if read_u32(data, 0) != JITDUMP_MAGIC {
+            return None;
+        }
+        // The header may grow in later versions, so it carries its own size.
+        offset = read_u32(data, 8) as usize;
+        if offset < JITDUMP_HEADER_SIZE {
+            return None;
+        }
+        if data.len() < offset {
+            return Some(0);
+        }
+    }
+
+    while offset + JITDUMP_RECORD_HEADER_SIZE <= data.len() {
+        let id = read_u32(data, offset);
+        let size = read_u32(data, offset + 4) as usize;
+        if size < JITDUMP_RECORD_HEADER_SIZE {
+            return None;
+        }
+        if offset + size > data.len() {
+            break;
+        }
+        let record = &data[offset + JITDUMP_RECORD_HEADER_SIZE..offset + size];
+        match id {
+            // pid, tid, vma, code_addr, code_size, code_index, name, code.
+            JIT_CODE_LOAD if record.len() >= 40 => {
+                let name = &record[40..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                insert(
+                    symbols,
+                    JitSymbol {
+                        address: read_u64(record, 16),
+                        size: read_u64(record, 24),
+                        name: String::from_utf8_lossy(name).into_owned(),
+                    },
+                );
+            }
+            // pid, tid, vma, old_code_addr, new_code_addr, code_size, code_index.
+            JIT_CODE_MOVE if record.len() >= 48 => {
+                if let Some(mut symbol) = symbols.remove(&read_u64(record, 16)) {
+                    symbol.address = read_u64(record, 24);
+                    symbol.size = read_u64(record, 32);
+                    insert(symbols, symbol);
+                }
+            }
+            // Debug and unwinding info, and the end of the file.
+            _ => {}
+        }
+        offset += size;
+    }
+    Some(offset)
+}
+
+/// Returns the jitdump of this process. JITs map it so that perf can find it, and so can we.
+fn find_jitdump() -> Option<PathBuf> {
+    let suffix = format!("/jit-{}.dump", process::id());
+    let maps = fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines()
+        .filter_map(|line| line.find('/').map(|start| &line[start..]))
+        .find(|path| path.ends_with(&suffix))
+        .map(PathBuf::from)
+}
+
+/// Reads the jitdump as the JIT appends to it.
+struct JitDump {
+    file: Option<File>,
+    last_scan: Option<Instant>,
+    // Data read but not parsed yet, because it does not form complete records yet.
+    buf: Vec<u8>,
+    header_read: bool,
+    // Set when the file turns out not to be a jitdump we understand.
+    invalid: bool,
+    symbols: Symbols,
+}
+
+impl JitDump {
+    fn new() -> Self {
+        JitDump {
+            file: None,
+            last_scan: None,
+            buf: Vec::new(),
+            header_read: false,
+            invalid: false,
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    /// Reads records appended since the last call, and returns whether there were any.
+    fn update(&mut self) -> bool {
+        if self.invalid {
+            return false;
+        }
+        if self.file.is_none() {
+            if let Some(last_scan) = self.last_scan {
+                if last_scan.elapsed() < JITDUMP_SCAN_INTERVAL {
+                    return false;
+                }
+            }
+            self.last_scan = Some(Instant::now());
+            self.file = find_jitdump().and_then(|path| File::open(path).ok());
+        }
+        let file = match self.file {
+            Some(ref mut file) => file,
+            None => return false,
+        };
+
+        let len = self.buf.len();
+        if file.read_to_end(&mut self.buf).is_err() || self.buf.len() == len {
+            return false;
+        }
+        match parse_jitdump(&self.buf, !self.header_read, &mut self.symbols) {
+            Some(0) => false,
+            // The records may all have been debug info, in which case this only costs rebuilding
+            // the map.
+            Some(consumed) => {
+                self.header_read = true;
+                self.buf.drain(..consumed);
+                true
+            }
+            None => {
+                self.invalid = true;
+                false
+            }
+        }
+    }
+}
+
+/// The JIT symbols known at some point in time.
+#[derive(Debug, Default)]
+pub struct JitMap {
+    // Sorted by address, and never overlapping.
+    symbols: Vec<JitSymbol>,
+}
+
+impl JitMap {
+    /// Returns the symbol containing `addr`.
+    pub fn lookup(&self, addr: usize) -> Option<&JitSymbol> {
+        let addr = addr as u64;
+        let index = match self
+            .symbols
+            .binary_search_by_key(&addr, |symbol| symbol.address)
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let symbol = &self.symbols[index];
+        if addr - symbol.address < symbol.size {
+            Some(symbol)
+        } else {
+            None
+        }
+    }
+}
+
+/// Keeps the current JitMap, and replaces it when JIT code is registered.
+pub struct JitCache {
+    current: Arc<JitMap>,
+    registry_generation: u64,
+    perf_map: PerfMap,
+    jitdump: JitDump,
+}
+
+impl JitCache {
+    pub fn new() -> Self {
+        JitCache {
+            current: Arc::new(JitMap::default()),
+            registry_generation: 0,
+            perf_map: PerfMap::new(PathBuf::from(format!("/tmp/perf-{}.map", process::id()))),
+            jitdump: JitDump::new(),
+        }
+    }
+
+    /// Returns the JIT symbols known right now.
+    ///
+    /// Like `ModuleCache::snapshot`, this must not be called while another thread is suspended,
+    /// since that thread may be registering JIT code and hold the lock this takes.
+    pub fn snapshot(&mut self) -> Arc<JitMap> {
+        let mut changed = self.perf_map.update();
+        changed |= self.jitdump.update();
+
+        let registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+        if !changed && registry.generation == self.registry_generation {
+            return self.current.clone();
+        }
+        self.registry_generation = registry.generation;
+
+        // Registered regions take precedence over the files, which take precedence over each
+        // other in no particular order.
+        let mut symbols = self.jitdump.symbols.clone();
+        for symbol in self.perf_map.symbols.values() {
+            insert(&mut symbols, symbol.clone());
+        }
+        for symbol in registry.symbols.values() {
+            insert(&mut symbols, symbol.clone());
+        }
+        self.current = Arc::new(JitMap {
+            symbols: symbols.into_values().collect(),
+        });
+        self.current.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+
+    fn symbol(address: u64, size: u64, name: &str) -> JitSymbol {
+        JitSymbol {
+            address,
+            size,
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_insert_replaces_overlapping() {
+        let mut symbols = BTreeMap::new();
+        insert(&mut symbols, symbol(0x1000, 0x100, "a"));
+        insert(&mut symbols, symbol(0x1100, 0x100, "b"));
+        insert(&mut symbols, symbol(0x1200, 0x100, "c"));
+        // Overlaps the end of a and the start of b.
+        insert(&mut symbols, symbol(0x10f0, 0x20, "d"));
+        let names: Vec<_> = symbols.values().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["d", "c"]);
+    }
+
+    #[test]
+    fn test_parse_perf_map_line() {
+        assert_eq!(
+            parse_perf_map_line("7f0012340000 1a0 wasm-function[3] (tier 2)\n"),
+            Some(symbol(0x7f00_1234_0000, 0x1a0, "wasm-function[3] (tier 2)"))
+        );
+        assert_eq!(
+            parse_perf_map_line("0x1000 0x10 f\n"),
+            Some(symbol(0x1000, 0x10, "f"))
+        );
+        assert_eq!(parse_perf_map_line("garbage\n"), None);
+    }
+
+    #[test]
+    fn test_perf_map_appends() {
+        let path = env::temp_dir().join(format!("vignette-perf-{}.map", process::id()));
+        let mut file = File::create(&path).unwrap();
+        let mut perf_map = PerfMap::new(path.clone());
+
+        write!(file, "1000 10 first\n2000 10 sec").unwrap();
+        assert!(perf_map.update());
+        assert_eq!(perf_map.symbols.len(), 1);
+        // The rest of the partially written line.
+        writeln!(file, "ond").unwrap();
+        assert!(perf_map.update());
+        assert_eq!(perf_map.symbols[&0x2000].name, "second");
+        assert!(!perf_map.update());
+        fs::remove_file(path).unwrap();
+    }
+
+    fn jitdump_record(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&id.to_ne_bytes());
+        record.extend_from_slice(&((JITDUMP_RECORD_HEADER_SIZE + body.len()) as u32).to_ne_bytes());
+        record.extend_from_slice(&0u64.to_ne_bytes());
+        record.extend_from_slice(body);
+        record
+    }
+
+    fn code_load(addr: u64, size: u64, name: &str) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&1u32.to_ne_bytes());
+        body.extend_from_slice(&2u32.to_ne_bytes());
+        for value in &[addr, addr, size, 0] {
+            body.extend_from_slice(&value.to_ne_bytes());
+        }
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        // The code itself.
+        body.extend_from_slice(&[0x90; 4]);
+        jitdump_record(JIT_CODE_LOAD, &body)
+    }
+
+    #[test]
+    fn test_parse_jitdump() {
+        let mut data = Vec::new();
+        for value in &[JITDUMP_MAGIC, 1, JITDUMP_HEADER_SIZE as u32, 62, 0, 1] {
+            data.extend_from_slice(&value.to_ne_bytes());
+        }
+        data.extend_from_slice(&[0; 16]);
+        data.extend(code_load(0x1000, 0x40, "jitted"));
+        let mut moved = Vec::new();
+        moved.extend_from_slice(&[0; 8]);
+        for value in &[0x1000u64, 0x1000, 0x5000, 0x40, 0] {
+            moved.extend_from_slice(&value.to_ne_bytes());
+        }
+        data.extend(jitdump_record(JIT_CODE_MOVE, &moved[..48]));
+        let complete = data.len();
+        // A record that is still being written.
+        data.extend_from_slice(&code_load(0x9000, 0x10, "partial")[..20]);
+
+        let mut symbols = BTreeMap::new();
+        assert_eq!(parse_jitdump(&data, true, &mut symbols), Some(complete));
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[&0x5000], symbol(0x5000, 0x40, "jitted"));
+
+        assert_eq!(parse_jitdump(&[0; 64], true, &mut symbols), None);
+    }
+
+    #[test]
+    fn test_registered_regions() {
+        // The registry is global, so only look at addresses no other test registers.
+        let mut cache = JitCache::new();
+        let before = cache.snapshot();
+        register_jit_region(0x10_0000, 0x100, "registered");
+        let after = cache.snapshot();
+
+        assert_eq!(after.lookup(0x10_0080).unwrap().name, "registered");
+        assert!(after.lookup(0x10_0100).is_none());
+        assert!(after.lookup(0xf_ffff).is_none());
+        assert!(before.lookup(0x10_0080).is_none());
+        // Later snapshots still have it.
+        assert_eq!(
+            cache.snapshot().lookup(0x10_0000).unwrap().name,
+            "registered"
+        );
+    }
+}
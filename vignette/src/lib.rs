@@ -12,22 +12,28 @@ mod lib_mac;
 #[cfg(target_os = "macos")]
 pub use lib_mac::*;
 
+pub mod debuginfod;
 pub mod output;
 pub mod speedscope;
+pub mod symbol_server;
+pub mod symbolize;
 
+mod download;
+mod http;
+mod jit;
 mod module_cache;
 pub mod types;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, mem, sync::Arc};
 
+pub use jit::register_jit_region;
+use jit::{JitCache, JitMap};
+use module_cache::{ModuleCache, ModuleMap};
 use threadinfo::Thread as ThreadId;
-use types::{Frame, Unwinder};
+use types::{Sample, Unwinder};
 
 pub struct Profiler {
     sampler: Sampler,
-    // TODO: If we want to support programs that load and unload shared libraries, we will want to
-    // capture the state of all modules at the time of profile capture. Then we'd have to have a
-    // module cache here, and propagate it to the profile.
 }
 
 impl Profiler {
@@ -43,6 +49,8 @@ impl Profiler {
     pub fn session(&self) -> Session {
         Session {
             profiler: &self,
+            modules: ModuleCache::new(),
+            jit: JitCache::new(),
             threads: HashMap::new(),
         }
     }
@@ -50,21 +58,33 @@ impl Profiler {
 
 pub struct Session<'a> {
     profiler: &'a Profiler,
-    threads: HashMap<ThreadId, Vec<Vec<Frame>>>,
+    // Tracks modules being loaded and unloaded while the session runs.
+    modules: ModuleCache,
+    // Tracks code generated at runtime.
+    jit: JitCache,
+    threads: HashMap<ThreadId, Vec<RecordedSample>>,
 }
 
 impl<'a> Session<'a> {
     /// Samples one thread once.
     /// Panics if the thread is the sampling thread.
     pub fn sample_thread(&mut self, thread: ThreadId) {
-        let sample = self.sample_once(thread);
+        // Must happen before the thread is suspended, as it may hold the dynamic loader's lock, or
+        // the lock on registered JIT regions.
+        let modules = self.modules.snapshot();
+        let jit = self.jit.snapshot();
+        let frames = self.sample_once(thread);
         self.threads
             .entry(thread)
             .or_insert_with(|| Vec::new())
-            .push(sample);
+            .push(RecordedSample {
+                modules,
+                jit,
+                frames,
+            });
     }
 
-    fn sample_once(&self, thread: ThreadId) -> Vec<Frame> {
+    fn sample_once(&self, thread: ThreadId) -> Sample {
         // TODO: Want to make the sample sizes configurable.
         let unwinder = LibunwindUnwinder::new(150);
         // TODO: Need to think if this interface is the best.
@@ -79,6 +99,16 @@ impl<'a> Session<'a> {
             })
     }
 
+    /// Returns the samples collected so far, leaving the session empty.
+    ///
+    /// Long running sessions can use this to periodically hand samples to an
+    /// `output::stream::StreamWriter` instead of holding all of them in memory until `finish`.
+    pub fn drain(&mut self) -> Profile {
+        Profile {
+            threads: mem::take(&mut self.threads),
+        }
+    }
+
     pub fn finish(self) -> Profile {
         Profile {
             threads: self.threads,
@@ -86,10 +116,18 @@ impl<'a> Session<'a> {
     }
 }
 
+/// A sample, along with the modules that were loaded and the JIT code that existed when it was
+/// taken.
+struct RecordedSample {
+    modules: Arc<ModuleMap>,
+    jit: Arc<JitMap>,
+    frames: Sample,
+}
+
 /// In-memory profile. This is just an opaque container for now.
 /// Use the Outputter to obtain a serializable form with build IDs resolved.
 pub struct Profile {
-    threads: HashMap<ThreadId, Vec<Vec<Frame>>>,
+    threads: HashMap<ThreadId, Vec<RecordedSample>>,
 }
 
 // TODO: Can we also have an iterator interface where each iteration causes a sampling? That way it
@@ -3,156 +3,837 @@ extern crate hex;
 extern crate libc;
 extern crate memmap;
 
-use self::goblin::elf::note::NT_GNU_BUILD_ID;
-use self::goblin::elf::Elf;
-use self::memmap::MmapOptions;
-use std::ffi::CStr;
-use std::fs::File;
+use self::goblin::elf::{
+    header::{EM_386, EM_AARCH64, EM_ARM, EM_X86_64},
+    Elf,
+};
+use self::memmap::Mmap;
+use std::env;
+use std::ffi::{CStr, OsStr};
+use std::fs::{self, File};
+use std::io;
 use std::mem;
 use std::ops::Range;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use super::{breakpad_debug_id, native_arch, Diagnostic, DiagnosticKind, IdKind};
+
+const NT_GNU_BUILD_ID: u32 = 3;
+// Suffix the kernel appends to the target of /proc/self/exe once the executable is deleted.
+const DELETED_SUFFIX: &str = " (deleted)";
+// Size of the prefix of .text that breakpad hashes, and of the resulting identifier.
+const TEXT_HASH_PAGE_SIZE: usize = 4096;
+const TEXT_HASH_SIZE: usize = 16;
+// Pointer encodings used by .eh_frame_hdr, from the LSB's description of DWARF exception headers.
+// The low nibble is the format of the value, the high nibble what it is relative to.
+const DW_EH_PE_OMIT: u8 = 0xff;
+const DW_EH_PE_ABSPTR: u8 = 0x00;
+const DW_EH_PE_UDATA2: u8 = 0x02;
+const DW_EH_PE_UDATA4: u8 = 0x03;
+const DW_EH_PE_UDATA8: u8 = 0x04;
+const DW_EH_PE_SDATA2: u8 = 0x0a;
+const DW_EH_PE_SDATA4: u8 = 0x0b;
+const DW_EH_PE_SDATA8: u8 = 0x0c;
+const DW_EH_PE_PCREL: u8 = 0x10;
+const DW_EH_PE_DATAREL: u8 = 0x30;
 
 // we need to retrieve module name, GUID (build ID) and relative addr of IP.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ModuleInfo {
     pub name: String,
-    pub build_id: String,
+    /// Upper case hex identifier of the module, derived as described by `id_kind`.
+    pub code_id: String,
+    /// The identifier breakpad symbol stores use for the module. See `breakpad_debug_id`.
+    pub debug_id: String,
+    pub id_kind: IdKind,
+    /// Path the module was loaded from.
+    pub path: String,
+    /// Lowest address the module is mapped at.
+    pub base_address: usize,
+    /// Distance from `base_address` to the end of the highest mapped segment.
+    pub size: usize,
+    /// The address the module's file is linked at, which breakpad symbol files are relative to:
+    /// that of the PT_LOAD segment at file offset 0, or 0 if there is none.
+    pub image_vaddr: usize,
+    pub arch: String,
+    pub soname: Option<String>,
+    /// The separate debug file named by `.gnu_debuglink`.
+    pub debug_file: Option<String>,
 }
 
 pub type ModuleAndAddr = (ModuleInfo, usize);
 
-#[derive(Debug, Clone)]
-struct ExtraModuleInfo {
-    range: Range<usize>,
-    info: ModuleInfo,
+// glibc's counts of objects ever loaded and unloaded.
+type Generation = (libc::c_ulonglong, libc::c_ulonglong);
+
+/// Tells apart modules loaded from the same path at the same address, e.g. a library that was
+/// rebuilt and loaded again. The build ID in memory identifies what was loaded. Without one, the
+/// file on disk has to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Identity {
+    BuildId(Vec<u8>),
+    // Device, inode, modification time in seconds and nanoseconds, and size.
+    File(u64, u64, i64, i64, u64),
+    Unknown,
 }
 
-/// TODO
-pub struct ModuleCache {
-    // The ModuleCache keeps a mapping from address ranges of IPs to a ModuleInfo instance.
-    // Since it is not easy to know the upper limit of an IP, we instead preserve a start (base)
-    // address as the lower and upper bound at the beginning, and extend the upper bound every time
-    // we find that dladdr tells us a module we already know about. This is admittedly not
-    // efficient, but we will see. This is also Linux specific, Mac and Windows may be able to tell
-    // us the upper limit.
-    module_ranges: Vec<ExtraModuleInfo>,
+/// A shared object (or the executable) as reported by dl_iterate_phdr.
+#[derive(Debug)]
+struct LoadedModule {
+    path: PathBuf,
+    // Difference between the addresses in the ELF file and the addresses in memory.
+    load_bias: usize,
+    // See ModuleInfo::image_vaddr.
+    image_vaddr: usize,
+    identity: Identity,
+    // Address ranges of the PT_LOAD segments.
+    ranges: Vec<Range<usize>>,
+    // The GNU build ID from the loaded PT_NOTE segments, copied while the module was certain to be
+    // mapped.
+    build_id: Option<Vec<u8>>,
+    // Whether this is the vdso, which the kernel maps without a file behind it.
+    vdso: bool,
+    // None if we could not identify the module. This is read as soon as the module is enumerated,
+    // since the module may be unloaded by the time its addresses are looked up.
+    info: Option<ModuleInfo>,
+    // Problems encountered while reading `info`.
+    diagnostics: Vec<Diagnostic>,
+    // Sorted addresses of the functions in the module's file, from the unwind tables. Shared with
+    // later maps while the module stays loaded.
+    function_starts: Arc<Vec<usize>>,
 }
 
-// TODO: write more tests
-impl ModuleCache {
-    pub fn new() -> Self {
-        ModuleCache {
-            module_ranges: Vec::new(),
+/// Whether `range` lies within one of the loaded segments in `ranges`.
+fn is_mapped(ranges: &[Range<usize>], range: &Range<usize>) -> bool {
+    ranges
+        .iter()
+        .any(|load| load.start <= range.start && range.end <= load.end)
+}
+
+/// Reads the GNU build ID from the loaded PT_NOTE segments at `notes`, along with their alignment.
+///
+/// This deliberately does not touch the file on disk, which may have been replaced or deleted
+/// since it was loaded, or may not be readable at all.
+///
+/// Only safe while the module stays mapped, i.e. from `enumerate_callback`, which the dynamic
+/// loader calls with its lock held.
+unsafe fn read_build_id(
+    ranges: &[Range<usize>],
+    notes: &[(Range<usize>, usize)],
+) -> Option<Vec<u8>> {
+    for &(ref range, align) in notes {
+        // Only read notes that are actually mapped.
+        if !is_mapped(ranges, range) {
+            continue;
+        }
+        let notes = slice::from_raw_parts(range.start as *const u8, range.len());
+        if let Some(build_id) = find_build_id(notes, align) {
+            return Some(build_id.to_vec());
         }
     }
+    None
+}
 
-    fn find_existing(&self, addr: *const libc::c_void) -> Option<ExtraModuleInfo> {
-        let existing = self
-            .module_ranges
-            .iter()
-            .find(|module| module.range.contains(&(addr as usize)));
-        existing.map(|x| x.clone())
+/// Reads the start addresses of a module's functions from the binary search table in its loaded
+/// `.eh_frame_hdr` at `eh_frame_hdr`, which every function that can be unwound through has an entry
+/// in. Returns an empty list if the module has no such table.
+///
+/// Only safe while the module stays mapped, like `read_build_id`.
+unsafe fn read_function_starts(
+    ranges: &[Range<usize>],
+    eh_frame_hdr: Option<Range<usize>>,
+    load_bias: usize,
+) -> Vec<usize> {
+    let range = match eh_frame_hdr {
+        Some(range) => range,
+        None => return Vec::new(),
+    };
+    if !is_mapped(ranges, &range) {
+        return Vec::new();
+    }
+    let hdr = slice::from_raw_parts(range.start as *const u8, range.len());
+    parse_eh_frame_hdr(hdr, range.start)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|addr| addr.wrapping_sub(load_bias))
+        .collect()
+}
+
+impl LoadedModule {
+    fn read_identity(&self) -> Identity {
+        if let Some(ref build_id) = self.build_id {
+            return Identity::BuildId(build_id.clone());
+        }
+        match fs::metadata(&self.path) {
+            Ok(metadata) => Identity::File(
+                metadata.dev(),
+                metadata.ino(),
+                metadata.mtime(),
+                metadata.mtime_nsec(),
+                metadata.size(),
+            ),
+            Err(_) => Identity::Unknown,
+        }
+    }
+
+    /// Whether this is the same module as `other`, so that what is known about one holds for the
+    /// other.
+    fn same_as(&self, other: &LoadedModule) -> bool {
+        self.path == other.path
+            && self.load_bias == other.load_bias
+            && self.identity == other.identity
     }
 
-    fn relative_addr(info: &ExtraModuleInfo, addr: *const libc::c_void) -> usize {
-        assert!(info.range.contains(&(addr as usize)));
-        return (addr as usize) - info.range.start;
+    /// Identifies a module that is not an ELF file we can parse by its modification time and size.
+    fn mtime_size_id(&self) -> Option<(IdKind, String)> {
+        let metadata = fs::metadata(&self.path).ok()?;
+        let mtime = metadata
+            .modified()
+            .ok()?
+            .duration_since(UNIX_EPOCH)
+            .ok()?
+            .as_secs();
+        // Laid out like the code ID of PE files, which is the link timestamp and image size.
+        Some((
+            IdKind::MtimeSize,
+            format!("{:08X}{:X}", mtime, metadata.len()),
+        ))
     }
 
-    pub fn get_or_insert(&mut self, addr: *const libc::c_void) -> Option<ModuleAndAddr> {
-        if let Some(module) = self.find_existing(addr) {
-            let rva = Self::relative_addr(&module, addr);
-            return Some((module.info.clone(), rva));
+    /// Identifies and describes the module, along with any problems doing so. Only returns None
+    /// if the module could not be identified at all.
+    fn read_info(&self) -> (Option<ModuleInfo>, Vec<Diagnostic>) {
+        let mut diagnostics = Vec::new();
+        let path = self.path.to_string_lossy().into_owned();
+        let diagnostic = |kind| Diagnostic {
+            path: path.clone(),
+            kind,
+        };
+
+        // Metadata that is not mapped has to come from the file on disk, which describes whatever
+        // is at `path` now. That is not necessarily what was loaded, so the build ID is always read
+        // from memory, and the file is ignored if its build ID does not match.
+        let build_id = self.build_id.clone();
+        let mut not_elf = false;
+        let file = if self.vdso {
+            Ok(None)
+        } else {
+            read_elf_file(&self.path)
+        };
+        let elf = match file {
+            Ok(Some(elf)) => {
+                if build_id.is_some() && elf.build_id != build_id {
+                    diagnostics.push(diagnostic(DiagnosticKind::Replaced));
+                    None
+                } else {
+                    Some(elf)
+                }
+            }
+            Ok(None) => {
+                not_elf = !self.vdso;
+                None
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                diagnostics.push(diagnostic(DiagnosticKind::Deleted));
+                None
+            }
+            Err(e) => {
+                diagnostics.push(diagnostic(DiagnosticKind::Unreadable {
+                    error: e.to_string(),
+                }));
+                None
+            }
+        };
+
+        let id = match build_id {
+            Some(build_id) => Some((IdKind::BuildId, hex::encode_upper(build_id))),
+            None => match elf {
+                Some(ref elf) => elf.fallback_id.clone(),
+                None if not_elf => self.mtime_size_id(),
+                None => None,
+            },
+        };
+        let (id_kind, code_id) = match id {
+            Some(id) => id,
+            None => {
+                diagnostics.push(diagnostic(DiagnosticKind::Unidentified));
+                return (None, diagnostics);
+            }
+        };
+        if id_kind != IdKind::BuildId {
+            diagnostics.push(diagnostic(DiagnosticKind::NoBuildId { id_kind }));
         }
 
-        let mut mod_info: libc::Dl_info = unsafe { mem::uninitialized() };
-        let r = unsafe { libc::dladdr(addr as *const libc::c_void, &mut mod_info) };
-        if r == 0 {
-            // No matching shared object.
+        let name = self
+            .path
+            .file_name()
+            .unwrap_or_else(|| self.path.as_os_str());
+        // Modules without any segments are never enumerated.
+        let base_address = self
+            .ranges
+            .iter()
+            .map(|range| range.start)
+            .min()
+            .unwrap_or(0);
+        let end = self.ranges.iter().map(|range| range.end).max().unwrap_or(0);
+        let elf = elf.unwrap_or_default();
+        let info = ModuleInfo {
+            name: name.to_string_lossy().into_owned(),
+            debug_id: breakpad_debug_id(&code_id, id_kind),
+            code_id,
+            id_kind,
+            path: self.path.to_string_lossy().into_owned(),
+            base_address,
+            size: end - base_address,
+            image_vaddr: self.image_vaddr,
+            // A process can only load modules built for its own architecture, which is the best
+            // guess for files we can't read, like the vdso.
+            arch: elf.arch.unwrap_or_else(|| native_arch().to_owned()),
+            soname: elf.soname,
+            debug_file: elf.debug_file,
+        };
+        (Some(info), diagnostics)
+    }
+}
+
+/// One PT_LOAD segment of a loaded module.
+#[derive(Debug)]
+struct Segment {
+    range: Range<usize>,
+    // Index into ModuleMap::modules.
+    module: usize,
+}
+
+/// The modules loaded at some point in time. Maps instruction pointers to the module they belong
+/// to, and their offset within it.
+///
+/// Samples hold on to the map that was current when they were taken, so that addresses in modules
+/// that have been unloaded since can still be attributed.
+#[derive(Debug)]
+pub struct ModuleMap {
+    modules: Vec<LoadedModule>,
+    // The exact ranges of all loaded segments, sorted by start address so lookups can use a binary
+    // search. Segments of different modules never overlap.
+    segments: Vec<Segment>,
+    // The generation when modules were enumerated. It changes whenever the set of loaded modules
+    // does.
+    generation: Generation,
+}
+
+/// Keeps the current ModuleMap, and replaces it when modules are loaded or unloaded.
+pub struct ModuleCache {
+    current: Arc<ModuleMap>,
+}
+
+/// Reads a pointer encoded with `encoding` at `*offset` in `data`, which is mapped at `data_addr`,
+/// and advances `offset` past it.
+fn read_encoded(data: &[u8], offset: &mut usize, encoding: u8, data_addr: usize) -> Option<usize> {
+    let at = *offset;
+    let (size, signed) = match encoding & 0x0f {
+        DW_EH_PE_ABSPTR => (mem::size_of::<usize>(), false),
+        DW_EH_PE_UDATA2 => (2, false),
+        DW_EH_PE_UDATA4 => (4, false),
+        DW_EH_PE_UDATA8 => (8, false),
+        DW_EH_PE_SDATA2 => (2, true),
+        DW_EH_PE_SDATA4 => (4, true),
+        DW_EH_PE_SDATA8 => (8, true),
+        _ => return None,
+    };
+    let bytes = data.get(at..at + size)?;
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().enumerate() {
+        let shift = if cfg!(target_endian = "little") {
+            i
+        } else {
+            size - 1 - i
+        } * 8;
+        value |= u64::from(byte) << shift;
+    }
+    if signed {
+        let unused = 64 - size as u32 * 8;
+        value = ((value << unused) as i64 >> unused) as u64;
+    }
+    *offset += size;
+
+    let value = value as usize;
+    match encoding & 0x70 {
+        DW_EH_PE_ABSPTR => Some(value),
+        DW_EH_PE_PCREL => Some(data_addr.wrapping_add(at).wrapping_add(value)),
+        // In .eh_frame_hdr, data relative values are relative to the start of the section.
+        DW_EH_PE_DATAREL => Some(data_addr.wrapping_add(value)),
+        _ => None,
+    }
+}
+
+/// Returns the sorted initial locations of the FDEs in `hdr`, the contents of an `.eh_frame_hdr`
+/// section mapped at `hdr_addr`.
+fn parse_eh_frame_hdr(hdr: &[u8], hdr_addr: usize) -> Option<Vec<usize>> {
+    if hdr.len() < 4 || hdr[0] != 1 {
+        return None;
+    }
+    let (eh_frame_ptr_enc, fde_count_enc, table_enc) = (hdr[1], hdr[2], hdr[3]);
+    let mut offset = 4;
+    if eh_frame_ptr_enc != DW_EH_PE_OMIT {
+        read_encoded(hdr, &mut offset, eh_frame_ptr_enc, hdr_addr)?;
+    }
+    // Without the table, the FDEs could only be found by walking all of .eh_frame.
+    if fde_count_enc == DW_EH_PE_OMIT || table_enc == DW_EH_PE_OMIT {
+        return None;
+    }
+    let count = read_encoded(hdr, &mut offset, fde_count_enc, hdr_addr)?;
+
+    // Each entry is at least two bytes, so this does not trust `count` with the allocation.
+    let mut starts = Vec::with_capacity(count.min(hdr.len() / 2));
+    for _ in 0..count {
+        starts.push(read_encoded(hdr, &mut offset, table_enc, hdr_addr)?);
+        // The address of the FDE.
+        read_encoded(hdr, &mut offset, table_enc, hdr_addr)?;
+    }
+    Some(starts)
+}
+
+/// Returns the descriptor of the NT_GNU_BUILD_ID note in `notes`, which is laid out like the
+/// contents of a PT_NOTE segment with the given alignment.
+fn find_build_id(notes: &[u8], align: usize) -> Option<&[u8]> {
+    // Notes are 4 byte aligned, except for some newer ones (e.g. .note.gnu.property) that use 8.
+    let align = if align == 8 { 8 } else { 4 };
+    let align_up = |n: usize| (n + align - 1) & !(align - 1);
+    let word = |at: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&notes[at..at + 4]);
+        u32::from_ne_bytes(bytes)
+    };
+
+    let mut offset = 0;
+    while offset + 12 <= notes.len() {
+        let name_size = word(offset) as usize;
+        let desc_size = word(offset + 4) as usize;
+        let note_type = word(offset + 8);
+        let name_start = offset + 12;
+        let name_end = name_start.checked_add(name_size)?;
+        let desc_start = align_up(name_end);
+        let desc_end = desc_start.checked_add(desc_size)?;
+        if desc_end > notes.len() {
             return None;
         }
 
-        let cpath = unsafe { CStr::from_ptr(mod_info.dli_fname) };
-        // Theoretically, SHT_NOTE gets converted to PT_NOTE, which should always be loaded, so we
-        // can manually walk ELF headers and Phdrs to extract a build ID without having to re-mmap
-        // each file. Goblin can't do it since it is expecting a complete ELF file, but perhaps we
-        // can use segments of it. Something to optimize in the future.
-        let path = Path::new(cpath.to_str().expect("valid path"));
-        let file = File::open(&path).expect("valid file");
-        let mapped = unsafe { MmapOptions::new().map(&file).expect("mmap") };
-        let elf = Elf::parse(&mapped).expect("valid elf");
-        // aaaaa! go back to possibly parsing file section by section and doing the string table
-        // lookup ourselves.
-        let notes = elf.iter_note_headers(&mapped).unwrap();
-        let mut build_id_opt = None;
-        for note_r in notes {
-            let note = note_r.unwrap();
-            if note.name != "GNU" {
-                continue;
-            }
+        if note_type == NT_GNU_BUILD_ID && &notes[name_start..name_end] == b"GNU\0" {
+            return Some(&notes[desc_start..desc_end]);
+        }
+        offset = align_up(desc_end);
+    }
+    None
+}
+
+/// Breakpad's identifier for ELF files without a build ID: the first page of `.text`, XORed
+/// together in 16 byte blocks.
+fn hash_text(text: &[u8]) -> [u8; TEXT_HASH_SIZE] {
+    let mut hash = [0; TEXT_HASH_SIZE];
+    let page = &text[..text.len().min(TEXT_HASH_PAGE_SIZE)];
+    for block in page.chunks(TEXT_HASH_SIZE) {
+        for (h, b) in hash.iter_mut().zip(block) {
+            *h ^= b;
+        }
+    }
+    hash
+}
+
+/// Returns the CRC32 from the contents of a `.gnu_debuglink` section, which is the NUL terminated
+/// name of the debug file, padded to 4 bytes and followed by the CRC of that file.
+fn parse_debuglink(debuglink: &[u8]) -> Option<(&[u8], u32)> {
+    let name_len = debuglink.iter().position(|&b| b == 0)?;
+    let crc_start = (name_len + 1 + 3) & !3;
+    let crc = debuglink.get(crc_start..crc_start + 4)?;
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(crc);
+    Some((&debuglink[..name_len], u32::from_ne_bytes(bytes)))
+}
 
-            if note.n_type != NT_GNU_BUILD_ID {
-                continue;
+/// What we learn about a module from its ELF file on disk.
+#[derive(Debug, Default)]
+struct ElfFile {
+    build_id: Option<Vec<u8>>,
+    soname: Option<String>,
+    debug_file: Option<String>,
+    // An identifier for modules without a build ID: the `.text` hash if there is a `.text`
+    // section, the debuglink CRC otherwise.
+    fallback_id: Option<(IdKind, String)>,
+    // The architecture the file was built for, named as in breakpad symbol files. None for machines
+    // we don't know the breakpad name of.
+    arch: Option<String>,
+}
+
+/// Returns the breakpad name of the architecture with ELF machine `e_machine` and class `is_64`.
+fn elf_arch(e_machine: u16, is_64: bool) -> Option<&'static str> {
+    match (e_machine, is_64) {
+        (EM_X86_64, true) => Some("x86_64"),
+        (EM_386, false) => Some("x86"),
+        (EM_AARCH64, true) => Some("arm64"),
+        (EM_ARM, false) => Some("arm"),
+        _ => None,
+    }
+}
+
+/// Returns None if the file is not an ELF file goblin can parse.
+fn read_elf_file(path: &Path) -> io::Result<Option<ElfFile>> {
+    let file = File::open(path)?;
+    let map = unsafe { Mmap::map(&file) }?;
+    let elf = match Elf::parse(&map) {
+        Ok(elf) => elf,
+        Err(_) => return Ok(None),
+    };
+    let section = |name: &str| {
+        elf.section_headers
+            .iter()
+            .find(|sh| match elf.shdr_strtab.get(sh.sh_name) {
+                Some(Ok(sh_name)) => sh_name == name,
+                _ => false,
+            })
+            .and_then(|sh| {
+                let start = sh.sh_offset as usize;
+                map.get(start..start.checked_add(sh.sh_size as usize)?)
+            })
+    };
+
+    let debuglink = section(".gnu_debuglink").and_then(parse_debuglink);
+    let fallback_id = match section(".text") {
+        Some(text) if !text.is_empty() => {
+            Some((IdKind::TextHash, hex::encode_upper(hash_text(text))))
+        }
+        _ => debuglink.map(|(_, crc)| (IdKind::DebuglinkCrc, format!("{:08X}", crc))),
+    };
+    let build_id = elf.iter_note_headers(&map).and_then(|notes| {
+        notes
+            .filter_map(Result::ok)
+            .find(|note| note.n_type == NT_GNU_BUILD_ID && note.name == "GNU")
+            .map(|note| note.desc.to_vec())
+    });
+    Ok(Some(ElfFile {
+        build_id,
+        soname: elf.soname.map(str::to_owned),
+        debug_file: debuglink.map(|(name, _)| String::from_utf8_lossy(name).into_owned()),
+        fallback_id,
+        arch: elf_arch(elf.header.e_machine, elf.is_64).map(str::to_owned),
+    }))
+}
+
+extern "C" fn enumerate_callback(
+    info: *mut libc::dl_phdr_info,
+    _size: libc::size_t,
+    data: *mut libc::c_void,
+) -> libc::c_int {
+    let enumeration = unsafe { &mut *(data as *mut Enumeration) };
+    let info = unsafe { &*info };
+    enumeration.generation = (info.dlpi_adds, info.dlpi_subs);
+
+    let name = if info.dlpi_name.is_null() {
+        &[]
+    } else {
+        unsafe { CStr::from_ptr(info.dlpi_name) }.to_bytes()
+    };
+    // The main program is reported without a name.
+    let path = if name.is_empty() {
+        match env::current_exe() {
+            Ok(path) => {
+                // Keep the original path. Reading it fails either way, and is reported as such.
+                let bytes = path.as_os_str().as_bytes();
+                if bytes.ends_with(DELETED_SUFFIX.as_bytes()) {
+                    PathBuf::from(OsStr::from_bytes(
+                        &bytes[..bytes.len() - DELETED_SUFFIX.len()],
+                    ))
+                } else {
+                    path
+                }
             }
+            Err(_) => PathBuf::from("/proc/self/exe"),
+        }
+    } else {
+        PathBuf::from(OsStr::from_bytes(name))
+    };
 
-            build_id_opt = Some(hex::encode_upper(note.desc));
-            break;
+    let phdrs = unsafe { slice::from_raw_parts(info.dlpi_phdr, info.dlpi_phnum as usize) };
+    let load_bias = info.dlpi_addr as usize;
+    let range = |vaddr: usize, size: usize| {
+        let start = load_bias.wrapping_add(vaddr);
+        start..start + size
+    };
+    let ranges: Vec<_> = phdrs
+        .iter()
+        .filter(|phdr| phdr.p_type == libc::PT_LOAD)
+        .map(|phdr| range(phdr.p_vaddr as usize, phdr.p_memsz as usize))
+        .collect();
+    let notes: Vec<_> = phdrs
+        .iter()
+        .filter(|phdr| phdr.p_type == libc::PT_NOTE)
+        .map(|phdr| {
+            (
+                range(phdr.p_vaddr as usize, phdr.p_memsz as usize),
+                phdr.p_align as usize,
+            )
+        })
+        .collect();
+    let eh_frame_hdr = phdrs
+        .iter()
+        .find(|phdr| phdr.p_type == libc::PT_GNU_EH_FRAME)
+        .map(|phdr| range(phdr.p_vaddr as usize, phdr.p_memsz as usize));
+
+    // Like dump_syms, which makes the addresses in symbol files relative to this.
+    let image_vaddr = phdrs
+        .iter()
+        .find(|phdr| phdr.p_type == libc::PT_LOAD && phdr.p_offset == 0)
+        .map_or(0, |phdr| phdr.p_vaddr as usize);
+
+    // Nothing is mapped for this module.
+    let base = match ranges.iter().map(|range| range.start).min() {
+        Some(base) => base,
+        None => return 0,
+    };
+    // The loader holds its lock while calling us, so no other thread can unload the module while
+    // its memory is read here. Anything read from memory must be copied before returning.
+    let build_id = unsafe { read_build_id(&ranges, &notes) };
+    let mut module = LoadedModule {
+        path,
+        load_bias,
+        image_vaddr,
+        identity: Identity::Unknown,
+        ranges,
+        build_id,
+        vdso: base == enumeration.vdso_base,
+        info: None,
+        diagnostics: Vec::new(),
+        function_starts: Arc::new(Vec::new()),
+    };
+    module.identity = module.read_identity();
+    let known = enumeration
+        .previous
+        .and_then(|previous| previous.modules.iter().find(|old| old.same_as(&module)));
+    match known {
+        Some(known) => {
+            module.info = known.info.clone();
+            module.diagnostics = known.diagnostics.clone();
+            module.function_starts = known.function_starts.clone();
         }
+        None => {
+            module.function_starts =
+                Arc::new(unsafe { read_function_starts(&module.ranges, eh_frame_hdr, load_bias) });
+        }
+    }
+    enumeration.known.push(known.is_some());
+    enumeration.modules.push(module);
+    0
+}
 
-        if build_id_opt.is_none() {
-            // Could not retrieve build ID.
-            return None;
+struct Enumeration<'a> {
+    modules: Vec<LoadedModule>,
+    // Whether each module was in `previous`, so that its info was copied from there.
+    known: Vec<bool>,
+    generation: Generation,
+    // Where the kernel mapped the vdso, or 0 if there is none.
+    vdso_base: usize,
+    previous: Option<&'a ModuleMap>,
+}
+
+/// Returns all currently loaded modules and their segments. Modules that are also in `previous`
+/// come with what it knows about them.
+fn enumerate_modules(previous: Option<&ModuleMap>) -> Enumeration<'_> {
+    let mut enumeration = Enumeration {
+        modules: Vec::new(),
+        known: Vec::new(),
+        generation: (0, 0),
+        vdso_base: unsafe { libc::getauxval(libc::AT_SYSINFO_EHDR) } as usize,
+        previous,
+    };
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(enumerate_callback),
+            &mut enumeration as *mut Enumeration as *mut libc::c_void,
+        );
+    }
+    enumeration
+}
+
+extern "C" fn generation_callback(
+    info: *mut libc::dl_phdr_info,
+    _size: libc::size_t,
+    data: *mut libc::c_void,
+) -> libc::c_int {
+    let generation = unsafe { &mut *(data as *mut Generation) };
+    let info = unsafe { &*info };
+    *generation = (info.dlpi_adds, info.dlpi_subs);
+    // Every entry reports the same counts, so stop after the first one.
+    1
+}
+
+fn current_generation() -> Generation {
+    let mut generation = (0, 0);
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(generation_callback),
+            &mut generation as *mut Generation as *mut libc::c_void,
+        );
+    }
+    generation
+}
+
+impl ModuleMap {
+    /// Enumerates the modules loaded right now, reusing what `previous` already knows about modules
+    /// that are still loaded at the same address.
+    fn new(previous: Option<&ModuleMap>) -> Self {
+        let mut enumeration = enumerate_modules(previous);
+        // Reading the files behind new modules can take a while, so it is done once the loader's
+        // lock is released. It only uses what was copied from memory.
+        for (module, &known) in enumeration.modules.iter_mut().zip(&enumeration.known) {
+            if !known {
+                let (info, diagnostics) = module.read_info();
+                module.info = info;
+                module.diagnostics = diagnostics;
+            }
+        }
+        Self::from_modules(enumeration.modules, enumeration.generation)
+    }
+
+    fn from_modules(modules: Vec<LoadedModule>, generation: Generation) -> Self {
+        let mut segments = Vec::new();
+        for (index, module) in modules.iter().enumerate() {
+            for range in &module.ranges {
+                segments.push(Segment {
+                    range: range.clone(),
+                    module: index,
+                });
+            }
         }
 
-        let build_id = build_id_opt.unwrap();
-        let base = mod_info.dli_fbase as usize;
+        segments.sort_by_key(|segment| segment.range.start);
+        ModuleMap {
+            modules,
+            segments,
+            generation,
+        }
+    }
 
-        // Try to find a module info matching these attributes and adjust it's range if required.
-        // Otherwise insert one.
-        // Return it in both cases.
-        let name = path.file_name().expect("file name").to_str().expect("utf8");
+    fn find_segment(&self, addr: usize) -> Option<&Segment> {
+        let index = match self
+            .segments
+            .binary_search_by_key(&addr, |segment| segment.range.start)
         {
-            let existing = self.module_ranges.iter_mut().find(|module| {
-                module.info.name == name
-                    && module.info.build_id == build_id
-                    && module.range.start == base
-            });
-
-            if let Some(module) = existing {
-                assert!(addr as usize >= module.range.start);
-                module.range = module.range.start..((addr as usize) + 1).max(module.range.end);
-                let rva = Self::relative_addr(&module, addr);
-                return Some((module.info.clone(), rva));
-            }
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+        let segment = &self.segments[index];
+        if segment.range.contains(&addr) {
+            Some(segment)
+        } else {
+            None
         }
+    }
 
-        let new = ExtraModuleInfo {
-            range: (base..(addr as usize) + 1),
-            info: ModuleInfo {
-                name: name.to_string(),
-                build_id: build_id,
-            },
+    // Returns the address in the ELF file that was mapped to `addr`. This is what breakpad symbol
+    // files and DWARF refer to, and is not the offset from the lowest mapped address unless the
+    // first segment has a virtual address of 0. That is not the case for non-PIE executables,
+    // which are linked at e.g. 0x400000 and loaded without a bias, or for prelinked objects.
+    fn relative_addr(module: &LoadedModule, addr: usize) -> usize {
+        addr.wrapping_sub(module.load_bias)
+    }
+
+    /// Returns the module containing `addr` and the address it corresponds to in the module's
+    /// file.
+    pub fn lookup(&self, addr: usize) -> Option<ModuleAndAddr> {
+        let module = &self.modules[self.find_segment(addr)?.module];
+        let info = module.info.clone()?;
+        Some((info, Self::relative_addr(module, addr)))
+    }
+
+    /// Returns the address in the module's file of the function containing `addr`, as far as the
+    /// module's unwind tables tell. Those only record where functions start, so addresses between
+    /// functions are attributed to the function before them.
+    pub fn function_start(&self, addr: usize) -> Option<usize> {
+        let module = &self.modules[self.find_segment(addr)?.module];
+        let addr = Self::relative_addr(module, addr);
+        let index = match module.function_starts.binary_search(&addr) {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
         };
+        Some(module.function_starts[index])
+    }
 
-        self.module_ranges.push(new.clone());
-        let rva = Self::relative_addr(&new, addr);
-        Some((new.info, rva))
+    /// Returns the problems encountered identifying the modules in this map.
+    pub fn diagnostics<'a>(&'a self) -> impl Iterator<Item = &'a Diagnostic> + 'a {
+        self.modules
+            .iter()
+            .flat_map(|module| module.diagnostics.iter())
+    }
+}
+
+// TODO: write more tests
+impl ModuleCache {
+    pub fn new() -> Self {
+        ModuleCache {
+            current: Arc::new(ModuleMap::new(None)),
+        }
+    }
+
+    /// Returns the modules loaded right now.
+    ///
+    /// This only enumerates modules again if any were loaded or unloaded since the last call, so
+    /// it is cheap enough to call for every sample. It must not be called while another thread is
+    /// suspended, since that thread may hold the dynamic loader's lock.
+    pub fn snapshot(&mut self) -> Arc<ModuleMap> {
+        if current_generation() != self.current.generation {
+            self.current = Arc::new(ModuleMap::new(Some(&self.current)));
+        }
+        self.current.clone()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate goblin;
+    extern crate hex;
     extern crate libc;
-    use super::ModuleCache;
+    extern crate memmap;
+
+    use self::goblin::elf::{note::NT_GNU_BUILD_ID, Elf};
+    use self::memmap::MmapOptions;
+    use super::{
+        find_build_id, hash_text, parse_debuglink, parse_eh_frame_hdr, read_elf_file, Diagnostic,
+        DiagnosticKind, IdKind, Identity, LoadedModule, ModuleCache, ModuleInfo, ModuleMap,
+    };
+    use module_cache::native_arch;
     use std::env;
-    use std::ffi::CString;
-    
+    use std::ffi::{CStr, CString};
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::mem;
+    use std::ops::Range;
+    use std::os::unix::io::FromRawFd;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+
+    // Returns the module file name and RVA of addr according to dladdr.
+    fn dladdr(addr: *const libc::c_void) -> (String, usize) {
+        let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+        assert!(unsafe { libc::dladdr(addr, &mut info) } != 0);
+        let path = unsafe { CStr::from_ptr(info.dli_fname) }.to_str().unwrap();
+        (
+            path.rsplit('/').next().unwrap().to_owned(),
+            addr as usize - info.dli_fbase as usize,
+        )
+    }
 
     #[test]
     fn test_cache() {
         let mut cache = ModuleCache::new();
         let (entry, rva) = cache
-            .get_or_insert((&ModuleCache::new as *const _) as *const libc::c_void)
+            .snapshot()
+            .lookup(ModuleCache::new as *const libc::c_void as usize)
             .unwrap();
         assert_eq!(
             entry.name,
@@ -172,22 +853,479 @@ mod tests {
         let mutex_init = CString::new("pthread_mutex_init").unwrap();
         let mutex_init_addr = unsafe { libc::dlsym(handle, mutex_init.as_ptr()) };
         eprintln!("pthread_mutex_init {:?}", mutex_init_addr);
-        let (pthread_entry, init_rva) = cache.get_or_insert(mutex_init_addr).unwrap();
-        assert_eq!(pthread_entry.name, "libpthread.so.0");
+        let (pthread_entry, init_rva) = cache.snapshot().lookup(mutex_init_addr as usize).unwrap();
+        // Newer glibc versions implement pthreads in libc.so.6 itself.
+        assert_eq!((pthread_entry.name, init_rva), dladdr(mutex_init_addr));
 
         let mutex_destroy = CString::new("pthread_mutex_destroy").unwrap();
         let mutex_destroy_addr = unsafe { libc::dlsym(handle, mutex_destroy.as_ptr()) };
         eprintln!("pthread_mutex_destroy {:?}", mutex_destroy_addr);
-        let (pthread_entry2, destroy_rva) = cache.get_or_insert(mutex_destroy_addr).unwrap();
-        assert_eq!(pthread_entry, pthread_entry2);
-        assert!(init_rva < destroy_rva);
+        let (pthread_entry2, destroy_rva) = cache
+            .snapshot()
+            .lookup(mutex_destroy_addr as usize)
+            .unwrap();
+        assert_eq!(pthread_entry2.name, dladdr(mutex_init_addr).0);
+        assert_eq!(destroy_rva, dladdr(mutex_destroy_addr).1);
+        assert!(init_rva != destroy_rva);
         eprintln!("init RVA 0x{:x}", init_rva);
         eprintln!("destroy RVA 0x{:x}", destroy_rva);
     }
 
     #[test]
     fn test_rva() {
-        let _cache = ModuleCache::new();
+        let mut cache = ModuleCache::new();
+        let new_addr = ModuleCache::new as *const libc::c_void;
+        let (_, new_rva) = cache.snapshot().lookup(new_addr as usize).unwrap();
+        // The test binary is position independent, so its load bias is the lowest mapped address,
+        // which is what dladdr reports.
+        assert_eq!(new_rva, dladdr(new_addr).1);
+    }
+
+    #[test]
+    fn test_segments_sorted() {
+        let map = ModuleCache::new().snapshot();
+        assert!(!map.segments.is_empty());
+        for pair in map.segments.windows(2) {
+            assert!(pair[0].range.end <= pair[1].range.start);
+        }
+    }
+
+    #[test]
+    fn test_unseen_addresses() {
+        // Every address in an executable segment belongs to its module, even if it was never
+        // looked up before.
+        let map = ModuleCache::new().snapshot();
+        let new_addr = ModuleCache::new as *const libc::c_void as usize;
+        let (exe, _) = map.lookup(new_addr).unwrap();
+        let exe_index = map.find_segment(new_addr).unwrap().module;
+        let ranges = map
+            .segments
+            .iter()
+            .filter(|segment| segment.module == exe_index)
+            .map(|segment| segment.range.clone());
+        for range in ranges {
+            let (first, first_rva) = map.lookup(range.start).unwrap();
+            let (last, last_rva) = map.lookup(range.end - 1).unwrap();
+            assert_eq!(first, exe);
+            assert_eq!(last, exe);
+            assert_eq!(last_rva - first_rva, range.end - 1 - range.start);
+        }
+    }
+
+    #[test]
+    fn test_unmapped() {
+        let mut cache = ModuleCache::new();
+        assert!(cache.snapshot().lookup(0x10).is_none());
+    }
+
+    // A map with a single module loaded with the given bias and segments.
+    fn single_module_map(load_bias: usize, ranges: Vec<Range<usize>>) -> ModuleMap {
+        let module = LoadedModule {
+            path: PathBuf::from("/lib/libfoo.so"),
+            load_bias,
+            image_vaddr: 0,
+            identity: Identity::BuildId(vec![0xab, 0xcd]),
+            ranges,
+            build_id: Some(vec![0xab, 0xcd]),
+            vdso: false,
+            info: Some(ModuleInfo {
+                name: "libfoo.so".to_owned(),
+                code_id: "ABCD".to_owned(),
+                debug_id: "0000CDAB0000000000000000000000000".to_owned(),
+                id_kind: IdKind::BuildId,
+                path: "/lib/libfoo.so".to_owned(),
+                base_address: 0,
+                size: 0,
+                image_vaddr: 0,
+                arch: "x86_64".to_owned(),
+                soname: None,
+                debug_file: None,
+            }),
+            diagnostics: Vec::new(),
+            function_starts: Arc::new(Vec::new()),
+        };
+        ModuleMap::from_modules(vec![module], (1, 0))
+    }
+
+    #[test]
+    fn test_same_module() {
+        let module = |identity| {
+            let mut map = single_module_map(0x1000, vec![0x1000..0x2000, 0x3000..0x4000]);
+            let mut module = map.modules.pop().unwrap();
+            module.identity = identity;
+            module
+        };
+        let file = Identity::File(1, 2, 3, 4, 5);
+        assert!(module(file.clone()).same_as(&module(file.clone())));
+        // A library rebuilt and loaded again at the same address is a different module.
+        assert!(!module(file).same_as(&module(Identity::File(1, 2, 6, 7, 5))));
+        assert!(!module(Identity::BuildId(vec![1])).same_as(&module(Identity::BuildId(vec![2]))));
+        let mut moved = module(Identity::Unknown);
+        moved.load_bias = 0x2000;
+        assert!(!module(Identity::Unknown).same_as(&moved));
+    }
+
+    #[test]
+    fn test_relative_addr_pie() {
+        // Linked at 0, so addresses are offsets from the lowest mapped address.
+        let bias = 0x7f00_0000_0000;
+        let map = single_module_map(
+            bias,
+            vec![bias..bias + 0x1000, bias + 0x2000..bias + 0x5000],
+        );
+        assert_eq!(map.lookup(bias + 0x2345).unwrap().1, 0x2345);
+    }
+
+    #[test]
+    fn test_relative_addr_non_pie() {
+        // Linked at 0x400000 and loaded there, without a bias.
+        let map = single_module_map(0, vec![0x40_0000..0x40_1000, 0x40_1000..0x48_0000]);
+        assert_eq!(map.lookup(0x40_1234).unwrap().1, 0x40_1234);
+    }
+
+    #[test]
+    fn test_relative_addr_prelinked() {
+        // Prelinked at 0x3000_0000, but loaded elsewhere since that address was taken.
+        let start = 0x7f12_0000_0000;
+        let map = single_module_map(
+            start - 0x3000_0000,
+            vec![start..start + 0x8000, start + 0x9000..start + 0xa000],
+        );
+        assert_eq!(map.lookup(start + 0x1234).unwrap().1, 0x3000_1234);
+        assert_eq!(map.lookup(start + 0x9010).unwrap().1, 0x3000_9010);
+    }
+
+    #[test]
+    fn test_entry_point() {
+        // The entry point as reported by the kernel maps back to the one in the ELF header.
+        let entry = unsafe { libc::getauxval(libc::AT_ENTRY) } as usize;
+        let (_, relative_entry) = ModuleCache::new().snapshot().lookup(entry).unwrap();
+
+        let file = File::open(env::current_exe().unwrap()).unwrap();
+        let mapped = unsafe { MmapOptions::new().map(&file).unwrap() };
+        let elf = Elf::parse(&mapped).unwrap();
+        assert_eq!(relative_entry as u64, elf.entry);
+    }
+
+    #[test]
+    fn test_build_id_from_memory() {
+        let mut cache = ModuleCache::new();
+        let (entry, _) = cache
+            .snapshot()
+            .lookup(ModuleCache::new as *const libc::c_void as usize)
+            .unwrap();
+
+        let file = File::open(env::current_exe().unwrap()).unwrap();
+        let mapped = unsafe { MmapOptions::new().map(&file).unwrap() };
+        let elf = Elf::parse(&mapped).unwrap();
+        let note = elf
+            .iter_note_headers(&mapped)
+            .unwrap()
+            .map(|note| note.unwrap())
+            .find(|note| note.name == "GNU" && note.n_type == NT_GNU_BUILD_ID)
+            .unwrap();
+        assert_eq!(entry.code_id, hex::encode_upper(note.desc));
+        assert_eq!(entry.debug_id.len(), 33);
+    }
+
+    #[test]
+    fn test_find_build_id() {
+        fn note(n_type: u32, name: &[u8], desc: &[u8], align: usize) -> Vec<u8> {
+            let mut note = Vec::new();
+            note.extend_from_slice(&(name.len() as u32).to_ne_bytes());
+            note.extend_from_slice(&(desc.len() as u32).to_ne_bytes());
+            note.extend_from_slice(&n_type.to_ne_bytes());
+            for part in &[name, desc] {
+                note.extend_from_slice(part);
+                while note.len() % align != 0 {
+                    note.push(0);
+                }
+            }
+            note
+        }
+
+        // NT_GNU_ABI_TAG, then the build ID.
+        let mut notes = note(1, b"GNU\0", &[0, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0], 4);
+        notes.extend(note(3, b"GNU\0", &[0xab, 0xcd, 0xef], 4));
+        assert_eq!(find_build_id(&notes, 4), Some(&[0xab, 0xcd, 0xef][..]));
+
+        // A .note.gnu.property style 8 byte aligned segment.
+        let mut notes = note(5, b"GNU\0", &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12], 8);
+        notes.extend(note(3, b"GNU\0", &[0x12; 20], 8));
+        assert_eq!(find_build_id(&notes, 8), Some(&[0x12; 20][..]));
+
+        // Other vendors' notes and truncated notes are ignored.
+        let notes = note(3, b"Xen\0", &[1, 2, 3, 4], 4);
+        assert_eq!(find_build_id(&notes, 4), None);
+        let notes = note(3, b"GNU\0", &[1, 2, 3, 4], 4);
+        assert_eq!(find_build_id(&notes[..notes.len() - 1], 4), None);
+    }
+
+    #[test]
+    fn test_parse_eh_frame_hdr() {
+        // As written by GNU ld: a PC relative pointer to .eh_frame, the count as a udata4, and a
+        // table of sdata4 pairs relative to the start of the section.
+        let mut hdr = vec![1, 0x1b, 0x03, 0x3b];
+        for value in &[0x100i32, 2, -0x10, 0x40, 0x20, 0x50] {
+            hdr.extend_from_slice(&value.to_ne_bytes());
+        }
+        assert_eq!(parse_eh_frame_hdr(&hdr, 0x1000), Some(vec![0xff0, 0x1020]));
+        // Truncated tables are ignored.
+        assert_eq!(parse_eh_frame_hdr(&hdr[..hdr.len() - 1], 0x1000), None);
+        // As is a header without a table.
+        assert_eq!(
+            parse_eh_frame_hdr(&[1, 0x1b, 0xff, 0xff, 0, 1, 0, 0], 0x1000),
+            None
+        );
+    }
+
+    #[test]
+    fn test_function_start() {
+        let map = ModuleCache::new().snapshot();
+        let function = hash_text as *const () as usize;
+        let (_, relative_function) = map.lookup(function).unwrap();
+        assert_eq!(map.function_start(function), Some(relative_function));
+        assert_eq!(map.function_start(function + 4), Some(relative_function));
+        assert_eq!(map.function_start(0x10), None);
+    }
+
+    #[test]
+    fn test_hash_text() {
+        let mut text = vec![0; 8192];
+        text[0] = 0x01;
+        text[16] = 0x03;
+        text[15] = 0xff;
+        // Past the first page.
+        text[4096] = 0x80;
+        let mut expected = [0; 16];
+        expected[0] = 0x02;
+        expected[15] = 0xff;
+        assert_eq!(hash_text(&text), expected);
+
+        // Short sections are hashed as they are.
+        assert_eq!(hash_text(&[1, 2, 3]), {
+            let mut expected = [0; 16];
+            expected[..3].copy_from_slice(&[1, 2, 3]);
+            expected
+        });
+    }
+
+    #[test]
+    fn test_parse_debuglink() {
+        let mut debuglink = b"libfoo.debug\0\0\0\0".to_vec();
+        debuglink.extend_from_slice(&0x1234_5678u32.to_ne_bytes());
+        assert_eq!(
+            parse_debuglink(&debuglink),
+            Some((&b"libfoo.debug"[..], 0x1234_5678))
+        );
+        // The CRC is missing.
+        assert_eq!(parse_debuglink(&debuglink[..16]), None);
+        assert_eq!(parse_debuglink(b"no terminator"), None);
+    }
+
+    #[test]
+    fn test_fallback_ids() {
+        // The test binary has a build ID, but also a .text section to hash.
+        let elf = read_elf_file(&env::current_exe().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(elf.arch.unwrap(), native_arch());
+        let (kind, id) = elf.fallback_id.unwrap();
+        assert_eq!(kind, IdKind::TextHash);
+        assert_eq!(id.len(), 32);
+
+        // Files that are not ELF fall back to their modification time and size.
+        let module = LoadedModule {
+            path: PathBuf::from(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/src/module_cache/linux.rs"
+            )),
+            load_bias: 0,
+            image_vaddr: 0,
+            identity: Identity::Unknown,
+            ranges: vec![0x1000..0x2000, 0x3000..0x3800],
+            build_id: None,
+            vdso: false,
+            info: None,
+            diagnostics: Vec::new(),
+            function_starts: Arc::new(Vec::new()),
+        };
+        assert!(read_elf_file(&module.path).unwrap().is_none());
+        let (info, diagnostics) = module.read_info();
+        let info = info.unwrap();
+        assert_eq!(info.name, "linux.rs");
+        assert_eq!(info.id_kind, IdKind::MtimeSize);
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                path: info.path.clone(),
+                kind: DiagnosticKind::NoBuildId {
+                    id_kind: IdKind::MtimeSize
+                },
+            }]
+        );
+        assert_eq!((info.base_address, info.size), (0x1000, 0x2800));
+        assert_eq!(info.soname, None);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let mut cache = ModuleCache::new();
+        let before = cache.snapshot();
+        assert!(Arc::ptr_eq(&before, &cache.snapshot()));
+
+        let lib = CString::new("libz.so.1").unwrap();
+        let handle = unsafe { libc::dlopen(lib.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        assert!(!handle.is_null());
+        let symbol = CString::new("zlibVersion").unwrap();
+        let addr = unsafe { libc::dlsym(handle, symbol.as_ptr()) } as usize;
+        assert!(addr != 0);
+
+        let loaded = cache.snapshot();
+        assert!(!Arc::ptr_eq(&before, &loaded));
+        assert!(before.lookup(addr).is_none());
+        let (module, _) = loaded.lookup(addr).unwrap();
+        assert_eq!(module.name, "libz.so.1");
+        assert_eq!(module.soname, Some("libz.so.1".to_owned()));
+        assert!(module.path.ends_with("/libz.so.1"));
+        assert!(module.base_address <= addr && addr < module.base_address + module.size);
+
+        unsafe { libc::dlclose(handle) };
+        let unloaded = cache.snapshot();
+        assert!(unloaded.lookup(addr).is_none());
+        // Samples taken while the library was loaded are still attributed to it.
+        assert_eq!(loaded.lookup(addr).unwrap().0, module);
+    }
+
+    // Returns the path of the library that defines `symbol`, loading it first.
+    fn library_path(name: &str, symbol: &str) -> PathBuf {
+        let name = CString::new(name).unwrap();
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        assert!(!handle.is_null());
+        let symbol = CString::new(symbol).unwrap();
+        let addr = unsafe { libc::dlsym(handle, symbol.as_ptr()) };
+        let mut info: libc::Dl_info = unsafe { mem::zeroed() };
+        assert!(unsafe { libc::dladdr(addr, &mut info) } != 0);
+        let path = unsafe { CStr::from_ptr(info.dli_fname) }.to_str().unwrap();
+        let path = PathBuf::from(path).canonicalize().unwrap();
+        unsafe { libc::dlclose(handle) };
+        path
+    }
+
+    // Loads a private copy of libz from `path`, and returns its handle and the address of
+    // zlibVersion in it.
+    fn load_zlib(path: &str) -> (*mut libc::c_void, usize) {
+        let path = CString::new(path).unwrap();
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        assert!(!handle.is_null());
+        let symbol = CString::new("zlibVersion").unwrap();
+        let addr = unsafe { libc::dlsym(handle, symbol.as_ptr()) } as usize;
+        assert!(addr != 0);
+        (handle, addr)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("vignette-{}-{}", process::id(), name))
+    }
+
+    fn has_diagnostic(map: &ModuleMap, path: &str, kind: DiagnosticKind) -> bool {
+        map.diagnostics()
+            .any(|diagnostic| diagnostic.path == path && diagnostic.kind == kind)
+    }
+
+    #[test]
+    fn test_vdso() {
+        let base = unsafe { libc::getauxval(libc::AT_SYSINFO_EHDR) } as usize;
+        if base == 0 {
+            return;
+        }
+        let map = ModuleCache::new().snapshot();
+        let (vdso, _) = map.lookup(base).unwrap();
+        // Identified from memory alone, without complaining about the missing file.
+        assert_eq!(vdso.id_kind, IdKind::BuildId);
+        assert!(map
+            .diagnostics()
+            .all(|diagnostic| diagnostic.path != vdso.path));
+    }
+
+    #[test]
+    fn test_deleted() {
+        let copy = temp_path("libz-deleted.so");
+        fs::copy(library_path("libz.so.1", "zlibVersion"), &copy).unwrap();
+        let copy = copy.to_str().unwrap();
+        let (handle, addr) = load_zlib(copy);
+        fs::remove_file(copy).unwrap();
+
+        let map = ModuleCache::new().snapshot();
+        let (module, _) = map.lookup(addr).unwrap();
+        assert_eq!(module.id_kind, IdKind::BuildId);
+        assert_eq!(module.soname, None);
+        assert!(has_diagnostic(&map, copy, DiagnosticKind::Deleted));
+        unsafe { libc::dlclose(handle) };
+    }
+
+    #[test]
+    fn test_replaced() {
+        let copy = temp_path("libz-replaced.so");
+        fs::copy(library_path("libz.so.1", "zlibVersion"), &copy).unwrap();
+        let copy = copy.to_str().unwrap();
+        let (handle, addr) = load_zlib(copy);
+        // Replace it with a different library, like a package upgrade would.
+        fs::remove_file(copy).unwrap();
+        fs::copy(library_path("libm.so.6", "cos"), copy).unwrap();
+
+        let map = ModuleCache::new().snapshot();
+        let (module, _) = map.lookup(addr).unwrap();
+        assert_eq!(module.id_kind, IdKind::BuildId);
+        // The SONAME of the replacement must not be used.
+        assert_eq!(module.soname, None);
+        assert!(has_diagnostic(&map, copy, DiagnosticKind::Replaced));
+        unsafe { libc::dlclose(handle) };
+        fs::remove_file(copy).unwrap();
+    }
+
+    #[test]
+    fn test_memfd() {
+        let name = CString::new("vignette-memfd").unwrap();
+        let fd = unsafe { libc::memfd_create(name.as_ptr(), 0) };
+        assert!(fd >= 0);
+        let mut memfd = unsafe { File::from_raw_fd(fd) };
+        let contents = fs::read(library_path("libz.so.1", "zlibVersion")).unwrap();
+        memfd.write_all(&contents).unwrap();
+        let path = format!("/proc/self/fd/{}", fd);
+        let (handle, addr) = load_zlib(&path);
+        // The path refers to nothing once the descriptor is closed.
+        drop(memfd);
+
+        let map = ModuleCache::new().snapshot();
+        let (module, _) = map.lookup(addr).unwrap();
+        assert_eq!(module.id_kind, IdKind::BuildId);
+        assert!(has_diagnostic(&map, &path, DiagnosticKind::Deleted));
+        unsafe { libc::dlclose(handle) };
+    }
+
+    #[test]
+    fn test_unreadable() {
+        // Directories can be opened, but not mapped.
+        let module = LoadedModule {
+            path: PathBuf::from("/"),
+            load_bias: 0,
+            image_vaddr: 0,
+            identity: Identity::Unknown,
+            ranges: vec![0x1000..0x2000, 0x3000..0x4000],
+            build_id: None,
+            vdso: false,
+            info: None,
+            diagnostics: Vec::new(),
+            function_starts: Arc::new(Vec::new()),
+        };
+        let (info, diagnostics) = module.read_info();
+        assert!(info.is_none());
+        match diagnostics[0].kind {
+            DiagnosticKind::Unreadable { .. } => {}
+            ref other => panic!("unexpected diagnostic {:?}", other),
+        }
+        assert_eq!(diagnostics[1].kind, DiagnosticKind::Unidentified);
     }
 
     #[test]
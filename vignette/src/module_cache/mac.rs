@@ -1,158 +1,355 @@
-extern crate goblin;
 extern crate hex;
 extern crate libc;
-extern crate memmap;
 
-use self::{
-    goblin::mach::{constants::cputype::CPU_TYPE_X86_64, load_command::CommandVariant, Mach},
-    memmap::MmapOptions,
-};
-use std::{ffi::CStr, fs::File, mem, ops::Range, path::Path};
+use std::ffi::CStr;
+use std::ops::Range;
+use std::path::Path;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
+
+use super::{Diagnostic, DiagnosticKind, IdKind};
+
+const MH_MAGIC_64: u32 = 0xfeed_facf;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_UUID: u32 = 0x1b;
+const LC_ID_DYLIB: u32 = 0x0d;
+const CPU_TYPE_X86_64: i32 = 0x0100_0007;
+const CPU_TYPE_ARM64: i32 = 0x0100_000c;
+// Size of mach_header_64, after which the load commands follow.
+const MACH_HEADER_64_SIZE: usize = 32;
+
+extern "C" {
+    fn _dyld_image_count() -> u32;
+    fn _dyld_get_image_header(image_index: u32) -> *const libc::c_void;
+    fn _dyld_get_image_vmaddr_slide(image_index: u32) -> libc::intptr_t;
+    fn _dyld_get_image_name(image_index: u32) -> *const libc::c_char;
+    fn _dyld_register_func_for_add_image(
+        func: extern "C" fn(header: *const libc::c_void, slide: libc::intptr_t),
+    );
+    fn _dyld_register_func_for_remove_image(
+        func: extern "C" fn(header: *const libc::c_void, slide: libc::intptr_t),
+    );
+}
 
 // we need to retrieve module name, GUID (build ID) and relative addr of IP.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ModuleInfo {
     pub name: String,
-    pub build_id: String,
+    /// Upper case hex identifier of the module, its LC_UUID.
+    pub code_id: String,
+    /// The identifier breakpad symbol stores use for the module.
+    pub debug_id: String,
+    pub id_kind: IdKind,
+    /// Path the module was loaded from.
+    pub path: String,
+    /// Address the Mach-O header is mapped at.
+    pub base_address: usize,
+    /// Distance from `base_address` to the end of the highest mapped segment.
+    pub size: usize,
+    /// What breakpad symbol files are relative to, in the terms of relative addresses. Always 0,
+    /// since both are relative to the Mach-O header.
+    pub image_vaddr: usize,
+    pub arch: String,
+    /// The install name of dylibs.
+    pub soname: Option<String>,
+    pub debug_file: Option<String>,
 }
 
 pub type ModuleAndAddr = (ModuleInfo, usize);
 
-#[derive(Debug, Clone)]
-struct ExtraModuleInfo {
-    range: Range<usize>,
-    info: ModuleInfo,
+// Counts of images dyld has added and removed, as reported to the callbacks below.
+static IMAGES_ADDED: AtomicUsize = AtomicUsize::new(0);
+static IMAGES_REMOVED: AtomicUsize = AtomicUsize::new(0);
+static REGISTER_CALLBACKS: Once = Once::new();
+
+extern "C" fn image_added(_header: *const libc::c_void, _slide: libc::intptr_t) {
+    IMAGES_ADDED.fetch_add(1, Ordering::SeqCst);
 }
 
-/// TODO
-pub struct ModuleCache {
-    // The ModuleCache keeps a mapping from address ranges of IPs to a ModuleInfo instance.
-    // Since it is not easy to know the upper limit of an IP, we instead preserve a start (base)
-    // address as the lower and upper bound at the beginning, and extend the upper bound every time
-    // we find that dladdr tells us a module we already know about. This is admittedly not
-    // efficient, but we will see. This is also Linux specific, Mac and Windows may be able to tell
-    // us the upper limit.
-    module_ranges: Vec<ExtraModuleInfo>,
+extern "C" fn image_removed(_header: *const libc::c_void, _slide: libc::intptr_t) {
+    IMAGES_REMOVED.fetch_add(1, Ordering::SeqCst);
 }
 
-// TODO: write more tests
-impl ModuleCache {
-    pub fn new() -> Self {
-        ModuleCache {
-            module_ranges: Vec::new(),
-        }
-    }
+// The counts of images ever added and removed. It changes whenever the set of loaded images does.
+type Generation = (usize, usize);
 
-    fn find_existing(&self, addr: usize) -> Option<ExtraModuleInfo> {
-        let existing = self
-            .module_ranges
-            .iter()
-            .find(|module| module.range.contains(&(addr as usize)));
-        existing.map(|x| x.clone())
-    }
+fn current_generation() -> Generation {
+    // dyld calls the add callback for every image already loaded when it is registered.
+    REGISTER_CALLBACKS.call_once(|| unsafe {
+        _dyld_register_func_for_add_image(image_added);
+        _dyld_register_func_for_remove_image(image_removed);
+    });
+    (
+        IMAGES_ADDED.load(Ordering::SeqCst),
+        IMAGES_REMOVED.load(Ordering::SeqCst),
+    )
+}
 
-    fn relative_addr(info: &ExtraModuleInfo, addr: usize) -> usize {
-        assert!(info.range.contains(&(addr as usize)));
-        return (addr as usize) - info.range.start;
-    }
+/// An image as reported by dyld, described from its header and load commands in memory. Images
+/// in the dyld shared cache have no file of their own, so nothing is read from disk.
+#[derive(Debug)]
+struct LoadedModule {
+    // Address of the Mach-O header, which addresses in the module are relative to.
+    header: usize,
+    // Address ranges of the segments, other than __PAGEZERO.
+    ranges: Vec<Range<usize>>,
+    // None if we could not identify the module.
+    info: Option<ModuleInfo>,
+    // Problems encountered while reading `info`.
+    diagnostics: Vec<Diagnostic>,
+}
 
-    pub fn get_or_insert(&mut self, addr: usize) -> Option<ModuleAndAddr> {
-        if let Some(module) = self.find_existing(addr) {
-            let rva = Self::relative_addr(&module, addr);
-            return Some((module.info.clone(), rva));
+impl LoadedModule {
+    /// Reads the image with the given dyld index.
+    fn read(index: u32) -> Option<LoadedModule> {
+        let header = unsafe { _dyld_get_image_header(index) } as usize;
+        let name = unsafe { _dyld_get_image_name(index) };
+        // The image was removed since it was counted.
+        if header == 0 || name.is_null() {
+            return None;
         }
+        let slide = unsafe { _dyld_get_image_vmaddr_slide(index) } as usize;
+        let path = unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned();
+        let diagnostic = |kind| Diagnostic {
+            path: path.clone(),
+            kind,
+        };
 
-        let mut mod_info: libc::Dl_info = unsafe { mem::uninitialized() };
-        let r = unsafe { libc::dladdr(addr as *const libc::c_void, &mut mod_info) };
-        if r == 0 {
-            // No matching shared object.
-            return None;
+        let word = |at: usize| unsafe { *((header + at) as *const u32) };
+        if word(0) != MH_MAGIC_64 {
+            return Some(LoadedModule {
+                header,
+                ranges: Vec::new(),
+                info: None,
+                diagnostics: vec![diagnostic(DiagnosticKind::Unidentified)],
+            });
         }
+        let cputype = word(4) as i32;
+        let command_count = word(16) as usize;
+        let commands_size = word(20) as usize;
+        let commands = unsafe {
+            slice::from_raw_parts((header + MACH_HEADER_64_SIZE) as *const u8, commands_size)
+        };
+        let commands = read_load_commands(commands, command_count);
 
-        let cpath = unsafe { CStr::from_ptr(mod_info.dli_fname) };
-        // TODO: Reformat note for mach-o or actually implement direct binding read.
-        // Theoretically, SHT_NOTE gets converted to PT_NOTE, which should always be loaded, so we
-        // can manually walk ELF headers and Phdrs to extract a build ID without having to re-mmap
-        // each file. Goblin can't do it since it is expecting a complete ELF file, but perhaps we
-        // can use segments of it. Something to optimize in the future.
-        let path = Path::new(cpath.to_str().expect("valid path"));
-        let file = File::open(&path).expect("valid file");
-        let mapped = unsafe { MmapOptions::new().map(&file).expect("mmap") };
-        let parsed = Mach::parse(&mapped).expect("mach-o");
-        let macho = (match parsed {
-            Mach::Fat(march) => {
-                let mut idx = None;
-                for (i, arch) in march.iter_arches().enumerate() {
-                    if arch.is_ok() {
-                        if arch.unwrap().cputype() == CPU_TYPE_X86_64 {
-                            idx = Some(i);
-                            break;
-                        }
-                    }
-                }
-                idx.map(|i| march.get(i))
+        let ranges: Vec<_> = commands
+            .segments
+            .iter()
+            .filter(|(name, _)| name.as_str() != "__PAGEZERO")
+            .map(|(_, range)| {
+                let start = range.start.wrapping_add(slide);
+                start..start + range.len()
+            })
+            .collect();
+        let code_id = match commands.uuid {
+            Some(uuid) => hex::encode_upper(uuid),
+            None => {
+                return Some(LoadedModule {
+                    header,
+                    ranges,
+                    info: None,
+                    diagnostics: vec![diagnostic(DiagnosticKind::Unidentified)],
+                })
             }
-            Mach::Binary(macho) => {
-                if macho.header.cputype() == CPU_TYPE_X86_64 {
-                    Some(Ok(macho))
-                } else {
-                    None
+        };
+        let end = ranges.iter().map(|range| range.end).max().unwrap_or(header);
+        let name = Path::new(&path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.clone());
+        let arch = match cputype {
+            CPU_TYPE_X86_64 => "x86_64".to_owned(),
+            CPU_TYPE_ARM64 => "arm64".to_owned(),
+            other => format!("cputype {:#x}", other),
+        };
+        let info = ModuleInfo {
+            name,
+            // Breakpad uses the UUID as is, with an age of 0.
+            debug_id: format!("{}0", code_id),
+            code_id,
+            id_kind: IdKind::BuildId,
+            path: path.clone(),
+            base_address: header,
+            size: end.saturating_sub(header),
+            image_vaddr: 0,
+            arch,
+            soname: commands.install_name,
+            debug_file: None,
+        };
+        Some(LoadedModule {
+            header,
+            ranges,
+            info: Some(info),
+            diagnostics: Vec::new(),
+        })
+    }
+}
+
+/// What we learn about a module from its load commands.
+#[derive(Debug, Default)]
+struct LoadCommands {
+    // Names and unslid address ranges of the LC_SEGMENT_64 commands.
+    segments: Vec<(String, Range<usize>)>,
+    uuid: Option<Vec<u8>>,
+    install_name: Option<String>,
+}
+
+/// Reads `count` load commands from `commands`, stopping early at anything malformed.
+fn read_load_commands(commands: &[u8], count: usize) -> LoadCommands {
+    let word = |at: usize| -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(commands.get(at..at + 4)?);
+        Some(u32::from_ne_bytes(bytes))
+    };
+    let quad = |at: usize| -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(commands.get(at..at + 8)?);
+        Some(u64::from_ne_bytes(bytes))
+    };
+    let c_string = |bytes: &[u8]| {
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..len]).into_owned()
+    };
+
+    let mut result = LoadCommands::default();
+    let mut offset = 0;
+    for _ in 0..count {
+        let (cmd, size) = match (word(offset), word(offset + 4)) {
+            (Some(cmd), Some(size)) if size >= 8 => (cmd, size as usize),
+            _ => break,
+        };
+        let command = match commands.get(offset..offset + size) {
+            Some(command) => command,
+            None => break,
+        };
+        match cmd {
+            LC_SEGMENT_64 if size >= 48 => {
+                if let (Some(vmaddr), Some(vmsize)) = (quad(offset + 24), quad(offset + 32)) {
+                    let start = vmaddr as usize;
+                    result
+                        .segments
+                        .push((c_string(&command[8..24]), start..start + vmsize as usize));
                 }
             }
-            _ => None,
-        })
-        .expect("non-none")
-        .expect("valid mach-o");
-
-        let mut build_id_opt = None;
-        for cmd in macho.load_commands {
-            match cmd.command {
-                CommandVariant::Uuid(uuid_command) => {
-                    build_id_opt = Some(hex::encode_upper(uuid_command.uuid));
-                    eprintln!("{:?}", build_id_opt);
-                    break;
+            LC_UUID if size >= 24 => result.uuid = Some(command[8..24].to_vec()),
+            LC_ID_DYLIB if size >= 24 => {
+                let name_offset = word(offset + 8).unwrap_or(0) as usize;
+                if let Some(name) = command.get(name_offset..) {
+                    result.install_name = Some(c_string(name));
                 }
-                _ => {}
             }
+            _ => {}
         }
+        offset += size;
+    }
+    result
+}
 
-        if build_id_opt.is_none() {
-            // Could not retrieve build ID.
-            return None;
+/// The modules loaded at some point in time. Maps instruction pointers to the module they belong
+/// to, and their offset within it.
+///
+/// Samples hold on to the map that was current when they were taken, so that addresses in modules
+/// that have been unloaded since can still be attributed.
+#[derive(Debug)]
+pub struct ModuleMap {
+    modules: Vec<LoadedModule>,
+    generation: Generation,
+}
+
+/// Keeps the current ModuleMap, and replaces it when modules are loaded or unloaded.
+pub struct ModuleCache {
+    current: Arc<ModuleMap>,
+}
+
+impl ModuleMap {
+    /// Enumerates the modules loaded right now. Images loaded or unloaded while this runs may be
+    /// missed, and are picked up by the next snapshot.
+    fn new() -> Self {
+        let generation = current_generation();
+        let count = unsafe { _dyld_image_count() };
+        let modules = (0..count).filter_map(LoadedModule::read).collect();
+        ModuleMap {
+            modules,
+            generation,
         }
+    }
 
-        let build_id = build_id_opt.unwrap();
-        let base = mod_info.dli_fbase as usize;
-
-        // Try to find a module info matching these attributes and adjust it's range if required.
-        // Otherwise insert one.
-        // Return it in both cases.
-        let name = path.file_name().expect("file name").to_str().expect("utf8");
-        {
-            let existing = self.module_ranges.iter_mut().find(|module| {
-                module.info.name == name
-                    && module.info.build_id == build_id
-                    && module.range.start == base
-            });
+    fn find_module(&self, addr: usize) -> Option<&LoadedModule> {
+        self.modules
+            .iter()
+            .find(|module| module.ranges.iter().any(|range| range.contains(&addr)))
+    }
 
-            if let Some(module) = existing {
-                assert!(addr as usize >= module.range.start);
-                module.range = module.range.start..((addr as usize) + 1).max(module.range.end);
-                let rva = Self::relative_addr(&module, addr);
-                return Some((module.info.clone(), rva));
-            }
+    /// Returns the module containing `addr` and its offset from the module's Mach-O header.
+    pub fn lookup(&self, addr: usize) -> Option<ModuleAndAddr> {
+        let module = self.find_module(addr)?;
+        let info = module.info.clone()?;
+        Some((info, addr - module.header))
+    }
+
+    /// Returns the start of the function containing `addr`. Reading `__unwind_info` is not
+    /// supported yet, so this is always None on Mac.
+    pub fn function_start(&self, _addr: usize) -> Option<usize> {
+        None
+    }
+
+    /// Returns the problems encountered identifying the modules in this map.
+    pub fn diagnostics<'a>(&'a self) -> impl Iterator<Item = &'a Diagnostic> + 'a {
+        self.modules
+            .iter()
+            .flat_map(|module| module.diagnostics.iter())
+    }
+}
+
+impl ModuleCache {
+    pub fn new() -> Self {
+        ModuleCache {
+            current: Arc::new(ModuleMap::new()),
         }
+    }
 
-        let new = ExtraModuleInfo {
-            range: (base..(addr as usize) + 1),
-            info: ModuleInfo {
-                name: name.to_string(),
-                build_id: build_id,
-            },
-        };
+    /// Returns the modules loaded right now.
+    ///
+    /// This only enumerates modules again if any were loaded or unloaded since the last call, so
+    /// it is cheap enough to call for every sample. It must not be called while another thread is
+    /// suspended, since that thread may hold the dynamic loader's lock.
+    pub fn snapshot(&mut self) -> Arc<ModuleMap> {
+        if current_generation() != self.current.generation {
+            self.current = Arc::new(ModuleMap::new());
+        }
+        self.current.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_self() {
+        let map = ModuleCache::new().snapshot();
+        let addr = test_lookup_self as *const () as usize;
+        let (info, rva) = map.lookup(addr).expect("this test binary is loaded");
+        assert_eq!(info.id_kind, IdKind::BuildId);
+        assert_eq!(info.code_id.len(), 32);
+        assert!(rva < info.size);
+    }
 
-        self.module_ranges.push(new.clone());
-        let rva = Self::relative_addr(&new, addr);
-        Some((new.info, rva))
+    #[test]
+    fn test_read_load_commands() {
+        let mut commands = Vec::new();
+        // LC_UUID
+        commands.extend_from_slice(&LC_UUID.to_ne_bytes());
+        commands.extend_from_slice(&24u32.to_ne_bytes());
+        commands.extend_from_slice(&[0xab; 16]);
+        // A truncated command ends the list.
+        commands.extend_from_slice(&LC_SEGMENT_64.to_ne_bytes());
+        commands.extend_from_slice(&72u32.to_ne_bytes());
+        let read = read_load_commands(&commands, 2);
+        assert_eq!(read.uuid, Some(vec![0xab; 16]));
+        assert!(read.segments.is_empty());
     }
 }
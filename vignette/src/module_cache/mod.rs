@@ -1,3 +1,5 @@
+use std::env;
+
 #[cfg(target_os = "linux")]
 mod linux;
 #[cfg(target_os = "linux")]
@@ -7,3 +9,84 @@ pub use self::linux::*;
 mod mac;
 #[cfg(target_os = "macos")]
 pub use self::mac::*;
+
+/// How a module's identifier was derived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdKind {
+    /// The platform's build identifier: the GNU build ID on Linux, the LC_UUID on Mac.
+    BuildId,
+    /// XOR of the first page of `.text` in 16 byte blocks, which is what breakpad uses as the code
+    /// ID of ELF files without a build ID.
+    TextHash,
+    /// The CRC32 of the separate debug file, from `.gnu_debuglink`.
+    DebuglinkCrc,
+    /// Modification time and size of the file.
+    MtimeSize,
+    /// Not a module, but the synthetic module that frames in JIT-generated code are attributed to.
+    /// It has no identifier, and carries its symbols in the profile instead.
+    Jit,
+}
+
+/// A problem identifying or describing a loaded module. These are collected instead of failing,
+/// so that they can be reported in the profile.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// Path of the module, as reported by the dynamic loader.
+    pub path: String,
+    #[serde(flatten)]
+    pub kind: DiagnosticKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DiagnosticKind {
+    /// The module has no build ID, and was identified by a less reliable identifier instead.
+    NoBuildId { id_kind: IdKind },
+    /// The file was deleted since it was loaded, e.g. by an upgrade or because it was a memfd.
+    /// Whatever could not be read from memory is missing.
+    Deleted,
+    /// The file on disk is not the one that was loaded, so nothing was read from it.
+    Replaced,
+    /// The file could not be read for another reason.
+    Unreadable { error: String },
+    /// The module could not be identified at all. Its frames are recorded without a module.
+    Unidentified,
+}
+
+/// Derives the debug ID that breakpad keys symbol files on from a module's code ID, given in hex.
+///
+/// For ELF build IDs and text hashes, breakpad takes the first 16 bytes, zero padded, reads them as
+/// a little endian GUID and appends an age of 0. Identifiers breakpad does not know about are used
+/// as they are.
+pub fn breakpad_debug_id(code_id: &str, id_kind: IdKind) -> String {
+    match id_kind {
+        IdKind::BuildId | IdKind::TextHash => {}
+        IdKind::DebuglinkCrc | IdKind::MtimeSize | IdKind::Jit => return code_id.to_owned(),
+    }
+
+    let mut hex = code_id.to_uppercase();
+    hex.truncate(32);
+    while hex.len() < 32 {
+        hex.push('0');
+    }
+    let byte = |i: usize| &hex[i * 2..i * 2 + 2];
+    let mut debug_id = String::with_capacity(33);
+    // The first three GUID fields are 4, 2 and 2 byte integers; the rest is a byte array.
+    for &i in &[3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15] {
+        debug_id.push_str(byte(i));
+    }
+    debug_id.push('0');
+    debug_id
+}
+
+/// Returns the breakpad name of the architecture this process runs on, which all the modules it
+/// loads are built for. Breakpad and Rust name most architectures the same.
+pub fn native_arch() -> &'static str {
+    match env::consts::ARCH {
+        "aarch64" => "arm64",
+        "powerpc" => "ppc",
+        "powerpc64" => "ppc64",
+        arch => arch,
+    }
+}
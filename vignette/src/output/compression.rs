@@ -0,0 +1,263 @@
+// Transparent compression of serialized profiles.
+//
+// The codecs are only compiled in with the `compression` feature, so that applications that only
+// record profiles do not pay for them. The API is the same either way, so that enabling the
+// feature never breaks code written without it. Readers always detect compressed input by its
+// magic bytes, so that a build without the feature can at least report why it cannot read a
+// profile.
+
+#[cfg(feature = "compression")]
+extern crate flate2;
+#[cfg(feature = "compression")]
+extern crate zstd;
+
+use std::io::{self, BufRead, Chain, Cursor, Read, Write};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression to apply when writing a profile.
+///
+/// All variants exist whether or not the `compression` feature is enabled. Without it, writing
+/// with anything but `None` fails with an `Unsupported` error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+// The error for compressed input or output in builds without the `compression` feature.
+#[cfg(not(feature = "compression"))]
+fn unsupported(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} compression needs vignette to be built with the compression feature",
+            name
+        ),
+    )
+}
+
+enum EncoderInner<W: Write> {
+    None(W),
+    #[cfg(feature = "compression")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "compression")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+/// A writer that compresses everything written to it.
+///
+/// `finish` must be called to write out the end of the compressed stream.
+pub struct Encoder<W: Write>(EncoderInner<W>);
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W, compression: Compression) -> io::Result<Self> {
+        let inner = match compression {
+            Compression::None => EncoderInner::None(writer),
+            #[cfg(feature = "compression")]
+            Compression::Gzip => EncoderInner::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "compression")]
+            Compression::Zstd => EncoderInner::Zstd(zstd::Encoder::new(writer, 0)?),
+            #[cfg(not(feature = "compression"))]
+            Compression::Gzip => return Err(unsupported("gzip")),
+            #[cfg(not(feature = "compression"))]
+            Compression::Zstd => return Err(unsupported("zstd")),
+        };
+        Ok(Encoder(inner))
+    }
+
+    /// Returns the underlying writer, which holds the compressed stream written so far.
+    pub fn get_ref(&self) -> &W {
+        match self.0 {
+            EncoderInner::None(ref writer) => writer,
+            #[cfg(feature = "compression")]
+            EncoderInner::Gzip(ref encoder) => encoder.get_ref(),
+            #[cfg(feature = "compression")]
+            EncoderInner::Zstd(ref encoder) => encoder.get_ref(),
+        }
+    }
+
+    /// Completes the compressed stream and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self.0 {
+            EncoderInner::None(writer) => Ok(writer),
+            #[cfg(feature = "compression")]
+            EncoderInner::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "compression")]
+            EncoderInner::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0 {
+            EncoderInner::None(ref mut writer) => writer.write(buf),
+            #[cfg(feature = "compression")]
+            EncoderInner::Gzip(ref mut encoder) => encoder.write(buf),
+            #[cfg(feature = "compression")]
+            EncoderInner::Zstd(ref mut encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.0 {
+            EncoderInner::None(ref mut writer) => writer.flush(),
+            #[cfg(feature = "compression")]
+            EncoderInner::Gzip(ref mut encoder) => encoder.flush(),
+            #[cfg(feature = "compression")]
+            EncoderInner::Zstd(ref mut encoder) => encoder.flush(),
+        }
+    }
+}
+
+// The input with the magic bytes that were read to detect its compression put back in front.
+type Peeked<R> = Chain<Cursor<Vec<u8>>, R>;
+
+enum DecoderInner<R: BufRead> {
+    None(Peeked<R>),
+    #[cfg(feature = "compression")]
+    Gzip(flate2::bufread::MultiGzDecoder<Peeked<R>>),
+    #[cfg(feature = "compression")]
+    Zstd(zstd::Decoder<'static, Peeked<R>>),
+}
+
+/// A reader that decompresses its input if it starts with gzip or zstd magic bytes, and passes it
+/// through unchanged otherwise.
+pub struct Decoder<R: BufRead>(DecoderInner<R>);
+
+impl<R: BufRead> Decoder<R> {
+    /// Detects the compression of `reader` from its first bytes.
+    ///
+    /// Returns an `Unsupported` error for compressed input when the `compression` feature is
+    /// disabled.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        // Pipes and sockets may deliver the magic bytes in several reads.
+        let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+        while magic.len() < ZSTD_MAGIC.len() {
+            let read = {
+                let available = reader.fill_buf()?;
+                let wanted = (ZSTD_MAGIC.len() - magic.len()).min(available.len());
+                magic.extend_from_slice(&available[..wanted]);
+                wanted
+            };
+            if read == 0 {
+                break;
+            }
+            reader.consume(read);
+        }
+        let compression = if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        };
+        let reader = Cursor::new(magic).chain(reader);
+
+        let inner = match compression {
+            Compression::None => DecoderInner::None(reader),
+            #[cfg(feature = "compression")]
+            Compression::Gzip => DecoderInner::Gzip(flate2::bufread::MultiGzDecoder::new(reader)),
+            #[cfg(feature = "compression")]
+            Compression::Zstd => DecoderInner::Zstd(zstd::Decoder::with_buffer(reader)?),
+            #[cfg(not(feature = "compression"))]
+            Compression::Gzip => return Err(unsupported("gzip")),
+            #[cfg(not(feature = "compression"))]
+            Compression::Zstd => return Err(unsupported("zstd")),
+        };
+        Ok(Decoder(inner))
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0 {
+            DecoderInner::None(ref mut reader) => reader.read(buf),
+            #[cfg(feature = "compression")]
+            DecoderInner::Gzip(ref mut decoder) => decoder.read(buf),
+            #[cfg(feature = "compression")]
+            DecoderInner::Zstd(ref mut decoder) => decoder.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(compression: Compression) {
+        let mut encoder = Encoder::new(Vec::new(), compression).unwrap();
+        encoder.write_all(b"{\"hello\": \"world\"}").unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decoded = String::new();
+        Decoder::new(&compressed[..])
+            .unwrap()
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "{\"hello\": \"world\"}");
+    }
+
+    #[test]
+    fn test_none() {
+        roundtrip(Compression::None);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_gzip() {
+        roundtrip(Compression::Gzip);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_zstd() {
+        roundtrip(Compression::Zstd);
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn test_disabled() {
+        let err = Decoder::new(&[0x1f, 0x8b, 0x08, 0x00][..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let err = Encoder::new(Vec::new(), Compression::Zstd).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    // Hands out its input one byte per read, like a slow pipe.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn test_magic_across_reads() {
+        let mut decoded = String::new();
+        Decoder::new(io::BufReader::with_capacity(1, Trickle(b"{}")))
+            .unwrap()
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "{}");
+
+        // Gzip magic split over two reads is still detected.
+        let result = Decoder::new(io::BufReader::with_capacity(1, Trickle(GZIP_MAGIC)));
+        #[cfg(not(feature = "compression"))]
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
+        #[cfg(feature = "compression")]
+        assert!(result.is_ok());
+    }
+}
@@ -0,0 +1,918 @@
+extern crate serde_json;
+
+use std::{
+    collections::HashMap,
+    error, fmt,
+    hash::Hash,
+    io::{self, BufReader, Read, Write},
+    sync::Arc,
+};
+
+use self::{
+    compression::{Compression, Decoder, Encoder},
+    serde_json::{Map, Value},
+};
+pub mod compression;
+pub mod stream;
+
+pub use super::jit::JitSymbol;
+pub use super::module_cache::{Diagnostic, DiagnosticKind, IdKind};
+
+use super::{
+    jit::{jit_module_info, JitMap},
+    module_cache::{breakpad_debug_id, ModuleInfo, ModuleMap},
+    symbolize::{self, Symbolized, Symbolizer},
+    threadinfo::Thread as ThreadId,
+    types::Frame as InputFrame,
+    Profile as InputProfile, RecordedSample,
+};
+
+// Intermediate vignette format to serialize instruction pointers and module caches without
+// symbols. This is then converted to a format other tools can support once symbols are available.
+
+/// Version of the `Profile` and `ResolvedProfile` formats written by this crate.
+///
+/// Bump this whenever the serialized structures change in a way older readers can't handle, and
+/// add a migration to `MIGRATIONS` so that `load` can still read profiles written by older
+/// versions. Changes made since the last release go into the same version. The JSON schemas under
+/// `schema/` describe the current version.
+pub const FORMAT_VERSION: u32 = 2;
+
+// Obviously not an efficient output format.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct Frame {
+    /// Index into the profile's modules, or None if the IP did not map to any module we could
+    /// identify, e.g. unregistered JIT code or a module without any identifier.
+    pub module_index: Option<u32>,
+    /// The address the IP corresponds to in the module's file, as used by symbol files and DWARF,
+    /// or the absolute IP if `module_index` is None or refers to the JIT module.
+    pub relative_ip: u64,
+    /// Start of the function containing the IP, in the same terms as `relative_ip`, as found in
+    /// the module's unwind tables while recording. This allows aggregating by function before the
+    /// profile is symbolicated. None if the module has no unwind tables, or in profiles written
+    /// before version 2.
+    pub function_rva: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sample {
+    /// Index into a Vec<Frame>.
+    pub frames: Vec<usize>,
+}
+
+pub type Samples = Vec<Sample>;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Thread {
+    pub thread_id: ThreadId,
+    pub samples: Samples,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub struct Module {
+    pub name: String,
+    /// Upper case hex identifier of the module, as breakpad reports it in `INFO CODE_ID`. This is
+    /// only the build ID if `id_kind` is `IdKind::BuildId`.
+    pub code_id: String,
+    /// The identifier from breakpad's `MODULE` line, which symbol stores are keyed on.
+    pub debug_id: String,
+    pub id_kind: IdKind,
+    // The remaining fields are None in profiles written before version 2.
+    /// Path the module was loaded from.
+    pub path: Option<String>,
+    /// Lowest address the module was mapped at.
+    pub base_address: Option<u64>,
+    /// Size of the address range the module was mapped at.
+    pub size: Option<u64>,
+    /// The address in the module's file that breakpad symbol files are relative to, which is not
+    /// 0 for non-PIE executables and prelinked libraries. None if unknown, which is taken as 0.
+    pub image_vaddr: Option<u64>,
+    pub arch: Option<String>,
+    pub soname: Option<String>,
+    /// Name of the separate debug file from `.gnu_debuglink`.
+    pub debug_file: Option<String>,
+    /// Symbols of the sampled JIT code, sorted by address, if `id_kind` is `IdKind::Jit`. No
+    /// symbol files exist for JIT code, so the profile carries them instead.
+    pub jit_symbols: Option<Vec<JitSymbol>>,
+}
+
+impl From<ModuleInfo> for Module {
+    fn from(mi: ModuleInfo) -> Self {
+        Self {
+            name: mi.name,
+            code_id: mi.code_id,
+            debug_id: mi.debug_id,
+            id_kind: mi.id_kind,
+            path: Some(mi.path),
+            base_address: Some(mi.base_address as u64),
+            size: Some(mi.size as u64),
+            image_vaddr: Some(mi.image_vaddr as u64),
+            arch: Some(mi.arch),
+            soname: mi.soname,
+            debug_file: mi.debug_file,
+            jit_symbols: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Profile {
+    /// Always FORMAT_VERSION for profiles created by this crate.
+    pub version: u32,
+    pub modules: Vec<Module>,
+    /// Problems identifying the loaded modules, which explain frames without a module or with
+    /// missing module metadata.
+    pub diagnostics: Vec<Diagnostic>,
+    pub threads: Vec<Thread>,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct ResolvedFrame {
+    /// The function the address is in. With inlining, the function everything in `inlined` was
+    /// inlined into, and `file` and `line` are those of the outermost inlined call.
+    pub name: String,
+    pub file: String,
+    pub line: u32,
+    /// The calls that were inlined at the address, innermost first, so that each was called from
+    /// the next and the last one from `name`. Empty if the address is not in inlined code, or in
+    /// profiles written before version 2.
+    pub inlined: Vec<InlinedFrame>,
+}
+
+/// A logical frame of a `ResolvedFrame` that has no frame of its own at runtime because its
+/// function was inlined.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct InlinedFrame {
+    pub name: String,
+    pub file: String,
+    pub line: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResolvedProfile {
+    /// Always FORMAT_VERSION for profiles created by this crate.
+    pub version: u32,
+    pub modules: Vec<Module>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub threads: Vec<Thread>,
+    pub frames: Vec<ResolvedFrame>,
+}
+
+/// Several profiles converted together, e.g. the sessions of one multi-session recording. They
+/// share one table of modules, diagnostics and frames, so identical frames have the same index in
+/// all of them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProfileBatch {
+    /// Always FORMAT_VERSION for batches created by this crate.
+    pub version: u32,
+    pub modules: Vec<Module>,
+    pub diagnostics: Vec<Diagnostic>,
+    pub frames: Vec<Frame>,
+    /// The profiles, in the order they were given to `Outputter::output_batch`.
+    pub profiles: Vec<BatchProfile>,
+}
+
+/// The samples of one profile in a `ProfileBatch`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchProfile {
+    pub threads: Vec<Thread>,
+}
+
+/// Errors returned when loading a serialized profile.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// The profile was written by a newer version of vignette than this one.
+    UnsupportedVersion(u64),
+    /// The input is valid JSON, but not shaped like any known profile version.
+    Malformed(&'static str),
+    /// The profile is larger than the limit passed to `load_limited` once decompressed.
+    TooLarge(u64),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref e) => write!(f, "could not read profile: {}", e),
+            LoadError::Json(ref e) => write!(f, "could not parse profile: {}", e),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "profile format version {} is newer than the supported version {}",
+                version, FORMAT_VERSION
+            ),
+            LoadError::Malformed(reason) => write!(f, "malformed profile: {}", reason),
+            LoadError::TooLarge(limit) => {
+                write!(f, "profile is larger than {} bytes decompressed", limit)
+            }
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(e: serde_json::Error) -> Self {
+        LoadError::Json(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProfileKind {
+    Unresolved,
+    Resolved,
+    Batch,
+}
+
+/// Upgrades a profile of version `i` to version `i + 1` in place. The `version` field itself is
+/// updated by `upgrade`.
+type Migration = fn(&mut Map<String, Value>, ProfileKind);
+
+const MIGRATIONS: &[Migration] = &[upgrade_v0, upgrade_v1];
+
+/// Version 0 profiles predate the version field, but are otherwise identical to version 1.
+fn upgrade_v0(_profile: &mut Map<String, Value>, _kind: ProfileKind) {}
+
+/// Version 2 records more about modules and frames. Of its changes, only these need older profiles
+/// to be rewritten:
+///
+/// - `Module::build_id` became `code_id`, with `id_kind` saying what kind of identifier it is, and
+///   the breakpad `debug_id` next to it. Version 1 skipped modules without a build ID.
+/// - `diagnostics` was added. Version 1 did not report any.
+/// - Resolved frames got `inlined`. Version 1 resolvers only kept the innermost function, so there
+///   is nothing to recover.
+///
+/// The other new fields are optional, and stay None. `Frame::relative_ip` is now relative to the
+/// module's load bias rather than its lowest mapped address. The two only differ for non-PIE
+/// executables and prelinked objects, which version 1 does not record enough about to correct.
+fn upgrade_v1(profile: &mut Map<String, Value>, kind: ProfileKind) {
+    if let Some(Value::Array(modules)) = profile.get_mut("modules") {
+        for module in modules {
+            if let Value::Object(module) = module {
+                let code_id = match module.remove("build_id") {
+                    Some(Value::String(code_id)) => code_id,
+                    // Leave it to deserialization to reject the module.
+                    _ => continue,
+                };
+                module.insert(
+                    "debug_id".to_owned(),
+                    Value::from(breakpad_debug_id(&code_id, IdKind::BuildId)),
+                );
+                module.insert("code_id".to_owned(), Value::from(code_id));
+                module.insert("id_kind".to_owned(), Value::from("build_id"));
+            }
+        }
+    }
+    profile.insert("diagnostics".to_owned(), Value::Array(Vec::new()));
+    if kind != ProfileKind::Resolved {
+        return;
+    }
+    if let Some(Value::Array(frames)) = profile.get_mut("frames") {
+        for frame in frames {
+            if let Value::Object(frame) = frame {
+                frame.insert("inlined".to_owned(), Value::Array(Vec::new()));
+            }
+        }
+    }
+}
+
+fn upgrade(value: Value, kind: ProfileKind) -> Result<Value, LoadError> {
+    let mut profile = match value {
+        Value::Object(profile) => profile,
+        _ => return Err(LoadError::Malformed("profile is not a JSON object")),
+    };
+
+    let mut version = match profile.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or(LoadError::Malformed("version is not an unsigned integer"))?,
+    };
+    if version > u64::from(FORMAT_VERSION) {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    if kind == ProfileKind::Batch && version < 2 {
+        return Err(LoadError::Malformed("batches were added in version 2"));
+    }
+
+    while version < u64::from(FORMAT_VERSION) {
+        MIGRATIONS[version as usize](&mut profile, kind);
+        version += 1;
+    }
+    profile.insert("version".to_owned(), Value::from(version));
+    Ok(Value::Object(profile))
+}
+
+// Reads a profile of at most `max_size` bytes once decompressed.
+fn read_upgraded<R: Read>(reader: R, kind: ProfileKind, max_size: u64) -> Result<Value, LoadError> {
+    let mut reader = Decoder::new(BufReader::new(reader))?.take(max_size.saturating_add(1));
+    let value = serde_json::from_reader(&mut reader);
+    // Whatever went wrong, it was reading past the limit.
+    if reader.limit() == 0 {
+        return Err(LoadError::TooLarge(max_size));
+    }
+    upgrade(value?, kind)
+}
+
+/// Reads a `Profile` written by this or any older version of vignette, upgrading it to the current
+/// format in memory.
+///
+/// Compressed profiles are decompressed transparently if the `compression` feature is enabled.
+pub fn load<R: Read>(reader: R) -> Result<Profile, LoadError> {
+    load_limited(reader, u64::MAX)
+}
+
+/// Like `load`, but fails with `LoadError::TooLarge` for profiles larger than `max_size` bytes
+/// once decompressed, so that profiles from untrusted sources can't take unbounded memory.
+pub fn load_limited<R: Read>(reader: R, max_size: u64) -> Result<Profile, LoadError> {
+    let value = read_upgraded(reader, ProfileKind::Unresolved, max_size)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Like `load`, but for the output of symbolication.
+pub fn load_resolved<R: Read>(reader: R) -> Result<ResolvedProfile, LoadError> {
+    let value = read_upgraded(reader, ProfileKind::Resolved, u64::MAX)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Like `load`, but for the output of `Outputter::output_batch`.
+pub fn load_batch<R: Read>(reader: R) -> Result<ProfileBatch, LoadError> {
+    let value = read_upgraded(reader, ProfileKind::Batch, u64::MAX)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Serializes `profile` to `writer`, compressing it if requested.
+///
+/// Returns the writer once the compressed stream has been completed.
+pub fn save<W: Write>(profile: &Profile, writer: W, compression: Compression) -> io::Result<W> {
+    let mut encoder = Encoder::new(writer, compression)?;
+    serde_json::to_writer(&mut encoder, profile)?;
+    encoder.finish()
+}
+
+// TODO: VecHashMap shouldn't be in output.
+/// Want a structure where a list of unique items is maintained.
+/// Callers can query this with a key and retrieve the index of that key in the list. This index is
+/// guaranteed to never change. Otherwise it is inserted and the index retrieved.
+pub struct VecHashMap<V>
+where
+    V: Hash + Eq + Clone,
+{
+    vec: Vec<V>,
+    map: HashMap<V, usize>,
+}
+
+impl<V> VecHashMap<V>
+where
+    V: Hash + Eq + Clone,
+{
+    pub fn new() -> VecHashMap<V> {
+        VecHashMap {
+            vec: Vec::new(),
+            map: HashMap::new(),
+        }
+    }
+
+    pub fn get_or_insert(&mut self, item: V) -> usize {
+        if self.map.contains_key(&item) {
+            self.map[&item]
+        } else {
+            self.vec.push(item.clone());
+            let index = self.vec.len() - 1;
+            self.map.insert(item, index);
+            index
+        }
+    }
+
+    /// Returns a copy of the current state of the vector.
+    pub fn vec(&self) -> Vec<V> {
+        self.vec.clone()
+    }
+
+    /// Returns the items inserted so far, in insertion order.
+    pub fn items(&self) -> &[V] {
+        &self.vec
+    }
+}
+
+pub struct Outputter {
+    // Used to get a reduced serializable profile, where there is a common list of loaded modules
+    // and sampled frames. Each frame in frames index refers to a module in module_index by index.
+    // Each sample in the thread samples refers to a frame by the frame index.
+    module_index: VecHashMap<ModuleInfo>,
+    frames_index: VecHashMap<Frame>,
+    diagnostics_index: VecHashMap<Diagnostic>,
+    // Symbols of the JIT code that frames were attributed to, for the JIT module.
+    jit_symbols: VecHashMap<JitSymbol>,
+    // The module map of the previous sample, whose diagnostics have already been collected.
+    // Consecutive samples usually share one.
+    last_modules: Option<Arc<ModuleMap>>,
+}
+
+/// Converts in-memory profiles into their serializable form. Each sample is resolved against the
+/// modules that were loaded when it was taken.
+///
+/// An outputter converts a single profile or batch, and is consumed doing so. Its result only holds
+/// the modules and frames its own samples refer to.
+impl Outputter {
+    pub fn new() -> Outputter {
+        Outputter {
+            module_index: VecHashMap::new(),
+            frames_index: VecHashMap::new(),
+            diagnostics_index: VecHashMap::new(),
+            jit_symbols: VecHashMap::new(),
+            last_modules: None,
+        }
+    }
+
+    fn output_frame(&mut self, frame: InputFrame, modules: &ModuleMap, jit: &JitMap) -> Frame {
+        if let Some((module, rva)) = modules.lookup(frame.ip as usize) {
+            let module_pos = self.module_index.get_or_insert(module.clone());
+            return Frame {
+                module_index: Some(module_pos as u32),
+                relative_ip: rva as u64,
+                function_rva: modules
+                    .function_start(frame.ip as usize)
+                    .map(|rva| rva as u64),
+            };
+        }
+        match jit.lookup(frame.ip as usize) {
+            // JIT code has no file to be relative to, so the IP is kept as it is.
+            Some(symbol) => {
+                self.jit_symbols.get_or_insert(symbol.clone());
+                let module_pos = self.module_index.get_or_insert(jit_module_info());
+                Frame {
+                    module_index: Some(module_pos as u32),
+                    relative_ip: frame.ip as usize as u64,
+                    function_rva: Some(symbol.address),
+                }
+            }
+            // Keep the frame so the stack stays intact, and let consumers show the gap.
+            None => Frame {
+                module_index: None,
+                relative_ip: frame.ip as usize as u64,
+                function_rva: None,
+            },
+        }
+    }
+
+    fn output_sample(&mut self, sample: RecordedSample) -> Sample {
+        let seen = match self.last_modules {
+            Some(ref last) => Arc::ptr_eq(last, &sample.modules),
+            None => false,
+        };
+        if !seen {
+            for diagnostic in sample.modules.diagnostics() {
+                self.diagnostics_index.get_or_insert(diagnostic.clone());
+            }
+            self.last_modules = Some(sample.modules.clone());
+        }
+
+        let mut output_frames = Vec::with_capacity(sample.frames.len());
+        for frame in sample.frames {
+            let output_frame = self.output_frame(frame, &sample.modules, &sample.jit);
+            let frame_pos = self.frames_index.get_or_insert(output_frame);
+            output_frames.push(frame_pos);
+        }
+        Sample {
+            frames: output_frames,
+        }
+    }
+
+    fn output_module(&self, module: ModuleInfo) -> Module {
+        let id_kind = module.id_kind;
+        let mut module = Module::from(module);
+        if id_kind == IdKind::Jit {
+            let mut symbols = self.jit_symbols.vec();
+            symbols.sort();
+            module.jit_symbols = Some(symbols);
+        }
+        module
+    }
+
+    /// Converts `profile` like `output` does and saves it to `writer`.
+    pub fn output_to<W: Write>(
+        self,
+        profile: InputProfile,
+        writer: W,
+        compression: Compression,
+    ) -> io::Result<W> {
+        let profile = self.output(profile);
+        save(&profile, writer, compression)
+    }
+
+    pub fn output(mut self, profile: InputProfile) -> Profile {
+        let threads = self.output_threads(profile);
+        Profile {
+            version: FORMAT_VERSION,
+            threads: threads,
+            modules: self.output_modules(),
+            diagnostics: self.diagnostics_index.vec(),
+            frames: self.frames_index.vec(),
+        }
+    }
+
+    /// Converts `profile` like `output` does, and symbolicates it right away with the symbols of
+    /// the modules this process loaded, as `Symbolizer::for_loaded_modules` finds them.
+    ///
+    /// This saves running `generate_symbols` and `resolve` when debugging locally. It reads the
+    /// symbols of every sampled module from disk, so shipped applications should `output` their
+    /// profiles and leave symbolication to later.
+    pub fn output_resolved(self, profile: InputProfile) -> Result<Symbolized, symbolize::Error> {
+        Symbolizer::for_loaded_modules().symbolize(self.output(profile))
+    }
+
+    /// Converts several profiles into one batch, which lists every module and frame once no
+    /// matter how many of the profiles refer to it.
+    pub fn output_batch<I>(mut self, profiles: I) -> ProfileBatch
+    where
+        I: IntoIterator<Item = InputProfile>,
+    {
+        let profiles = profiles
+            .into_iter()
+            .map(|profile| BatchProfile {
+                threads: self.output_threads(profile),
+            })
+            .collect();
+        ProfileBatch {
+            version: FORMAT_VERSION,
+            modules: self.output_modules(),
+            diagnostics: self.diagnostics_index.vec(),
+            frames: self.frames_index.vec(),
+            profiles,
+        }
+    }
+
+    fn output_threads(&mut self, profile: InputProfile) -> Vec<Thread> {
+        let mut threads = Vec::new();
+        for (thread_id, samples) in profile.threads {
+            let mut output_samples = Vec::with_capacity(samples.len());
+            for sample in samples {
+                output_samples.push(self.output_sample(sample));
+            }
+
+            threads.push(Thread {
+                thread_id: thread_id,
+                samples: output_samples,
+            });
+        }
+        threads
+    }
+
+    fn output_modules(&self) -> Vec<Module> {
+        self.module_index
+            .vec()
+            .into_iter()
+            .map(|module| self.output_module(module))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jit::JitCache;
+    use module_cache::ModuleCache;
+    use std::fs::File;
+    use threadinfo;
+
+    #[test]
+    fn test_load_v0_resolved() {
+        let file = File::open(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/profiles/rayon-life.resolved.vignette"
+        ))
+        .expect("example profile");
+        let profile = load_resolved(file).expect("loaded");
+        assert_eq!(profile.version, FORMAT_VERSION);
+        assert_eq!(profile.modules.len(), 3);
+        assert_eq!(
+            profile.frames[0].name,
+            "rayon_demo::life::Board::living_neighbors"
+        );
+        assert!(profile.frames[0].inlined.is_empty());
+    }
+
+    #[test]
+    fn test_load_resolved_inlined() {
+        let frame = ResolvedFrame {
+            name: "outer".to_owned(),
+            file: "lib.rs".to_owned(),
+            line: 10,
+            inlined: vec![
+                InlinedFrame {
+                    name: "inner".to_owned(),
+                    file: "inner.rs".to_owned(),
+                    line: 3,
+                },
+                InlinedFrame {
+                    name: "middle".to_owned(),
+                    file: "lib.rs".to_owned(),
+                    line: 20,
+                },
+            ],
+        };
+        let profile = ResolvedProfile {
+            version: FORMAT_VERSION,
+            modules: Vec::new(),
+            diagnostics: Vec::new(),
+            threads: Vec::new(),
+            frames: vec![frame.clone()],
+        };
+        let serialized = serde_json::to_vec(&profile).unwrap();
+        let loaded = load_resolved(&serialized[..]).expect("loaded");
+        assert_eq!(loaded.frames, vec![frame]);
+    }
+
+    #[test]
+    fn test_load_current() {
+        let profile = Profile {
+            version: FORMAT_VERSION,
+            modules: vec![Module {
+                name: "libfoo.so".to_owned(),
+                code_id: "ABCD".to_owned(),
+                debug_id: "0000CDAB0000000000000000000000000".to_owned(),
+                id_kind: IdKind::TextHash,
+                path: Some("/usr/lib/libfoo.so".to_owned()),
+                base_address: Some(0x7f00_0000_0000),
+                size: Some(0x5000),
+                image_vaddr: None,
+                arch: Some("x86_64".to_owned()),
+                soname: Some("libfoo.so".to_owned()),
+                debug_file: None,
+                jit_symbols: None,
+            }],
+            diagnostics: vec![Diagnostic {
+                path: "/usr/lib/libbar.so".to_owned(),
+                kind: DiagnosticKind::Unreadable {
+                    error: "permission denied".to_owned(),
+                },
+            }],
+            threads: Vec::new(),
+            frames: vec![Frame {
+                module_index: Some(0),
+                relative_ip: 0x1234,
+                function_rva: Some(0x1200),
+            }],
+        };
+        let serialized = serde_json::to_vec(&profile).unwrap();
+        let loaded = load(&serialized[..]).expect("loaded");
+        assert_eq!(loaded.modules, profile.modules);
+        assert_eq!(loaded.diagnostics, profile.diagnostics);
+        assert_eq!(loaded.frames, profile.frames);
+    }
+
+    #[test]
+    fn test_save_load() {
+        #[allow(unused_mut)]
+        let mut compressions = vec![Compression::None];
+        #[cfg(feature = "compression")]
+        compressions.extend(&[Compression::Gzip, Compression::Zstd]);
+
+        for compression in compressions {
+            let profile = Profile {
+                version: FORMAT_VERSION,
+                modules: Vec::new(),
+                diagnostics: Vec::new(),
+                threads: Vec::new(),
+                frames: vec![Frame {
+                    module_index: Some(0),
+                    relative_ip: 0x1234,
+                    function_rva: Some(0x1200),
+                }],
+            };
+            let saved = save(&profile, Vec::new(), compression).unwrap();
+            let loaded = load(&saved[..]).expect("loaded");
+            assert_eq!(loaded.frames, profile.frames);
+
+            // Limits apply to the decompressed size.
+            let size = serde_json::to_vec(&profile).unwrap().len() as u64;
+            assert!(load_limited(&saved[..], size).is_ok());
+            match load_limited(&saved[..], size - 1) {
+                Err(LoadError::TooLarge(limit)) => assert_eq!(limit, size - 1),
+                other => panic!("unexpected result {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_load_v1() {
+        let serialized = r#"{
+            "version": 1,
+            "modules": [{
+                "name": "sample_once",
+                "build_id": "BFAA2814D2DB2EA508B6D967319FB6FEF5B14C2C"
+            }],
+            "threads": [],
+            "frames": [{"module_index": 0, "relative_ip": 4660}]
+        }"#;
+        let loaded = load(serialized.as_bytes()).expect("loaded");
+        assert_eq!(loaded.version, FORMAT_VERSION);
+        assert_eq!(loaded.frames[0].module_index, Some(0));
+        assert_eq!(loaded.frames[0].function_rva, None);
+        assert_eq!(loaded.modules[0].id_kind, IdKind::BuildId);
+        assert_eq!(
+            loaded.modules[0].code_id,
+            "BFAA2814D2DB2EA508B6D967319FB6FEF5B14C2C"
+        );
+        // As written by dump_syms for the same binary.
+        assert_eq!(
+            loaded.modules[0].debug_id,
+            "1428AABFDBD2A52E08B6D967319FB6FE0"
+        );
+        assert_eq!(loaded.modules[0].path, None);
+        assert!(loaded.diagnostics.is_empty());
+    }
+
+    #[test]
+    fn test_breakpad_debug_id() {
+        // Short identifiers are zero padded.
+        assert_eq!(
+            breakpad_debug_id("0123456789abcdef", IdKind::TextHash),
+            "67452301AB89EFCD00000000000000000"
+        );
+        assert_eq!(
+            breakpad_debug_id("5F3E2D1C0000", IdKind::MtimeSize),
+            "5F3E2D1C0000"
+        );
+    }
+
+    #[test]
+    fn test_unknown_module() {
+        let mut threads = HashMap::new();
+        threads.insert(
+            threadinfo::current_thread().unwrap(),
+            vec![RecordedSample {
+                modules: ModuleCache::new().snapshot(),
+                jit: JitCache::new().snapshot(),
+                // Nothing is mapped at this address.
+                frames: vec![InputFrame { ip: 0x10 }],
+            }],
+        );
+        let profile = Outputter::new().output(InputProfile { threads });
+        assert!(profile.modules.is_empty());
+        assert_eq!(
+            profile.frames,
+            vec![Frame {
+                module_index: None,
+                relative_ip: 0x10,
+                function_rva: None,
+            }]
+        );
+        assert_eq!(profile.threads[0].samples[0].frames, vec![0]);
+    }
+
+    #[test]
+    fn test_jit_frames() {
+        ::register_jit_region(0x30_0000, 0x100, "jitted");
+        let mut threads = HashMap::new();
+        threads.insert(
+            threadinfo::current_thread().unwrap(),
+            vec![RecordedSample {
+                modules: ModuleCache::new().snapshot(),
+                jit: JitCache::new().snapshot(),
+                frames: vec![InputFrame { ip: 0x30_0010 }, InputFrame { ip: 0x30_0100 }],
+            }],
+        );
+        let profile = Outputter::new().output(InputProfile { threads });
+        assert_eq!(profile.modules.len(), 1);
+        assert_eq!(profile.modules[0].id_kind, IdKind::Jit);
+        assert_eq!(
+            profile.modules[0].jit_symbols,
+            Some(vec![JitSymbol {
+                address: 0x30_0000,
+                size: 0x100,
+                name: "jitted".to_owned(),
+            }])
+        );
+        assert_eq!(
+            profile.frames,
+            vec![
+                Frame {
+                    module_index: Some(0),
+                    relative_ip: 0x30_0010,
+                    function_rva: Some(0x30_0000),
+                },
+                // Just past the end of the region.
+                Frame {
+                    module_index: None,
+                    relative_ip: 0x30_0100,
+                    function_rva: None,
+                },
+            ]
+        );
+    }
+
+    // A profile with one sample on the current thread, with the given instruction pointers.
+    fn input_profile(ips: &[u64]) -> InputProfile {
+        let mut threads = HashMap::new();
+        threads.insert(
+            threadinfo::current_thread().unwrap(),
+            vec![RecordedSample {
+                modules: ModuleCache::new().snapshot(),
+                jit: JitCache::new().snapshot(),
+                frames: ips.iter().map(|&ip| InputFrame { ip }).collect(),
+            }],
+        );
+        InputProfile { threads }
+    }
+
+    #[test]
+    fn test_output_self_contained() {
+        let ip = input_profile as *const () as u64;
+        let first = Outputter::new().output(input_profile(&[ip]));
+        assert_eq!(first.modules.len(), 1);
+        assert_eq!(first.frames.len(), 1);
+
+        let second = Outputter::new().output(input_profile(&[0x10]));
+        assert!(second.modules.is_empty());
+        assert_eq!(second.frames.len(), 1);
+        assert_eq!(second.frames[0].module_index, None);
+        assert_eq!(second.threads[0].samples[0].frames, vec![0]);
+    }
+
+    #[test]
+    fn test_output_resolved() {
+        extern crate libc;
+
+        // This test binary has DWARF. libc usually only has its dynamic symbols, unless its debug
+        // file is installed.
+        let ips = [
+            input_profile as *const () as u64,
+            libc::getpid as *const () as u64,
+        ];
+        let symbolized = Outputter::new()
+            .output_resolved(input_profile(&ips))
+            .unwrap();
+        let profile = symbolized.profile;
+        assert_eq!(profile.version, FORMAT_VERSION);
+        assert_eq!(profile.modules.len(), 2);
+        assert!(profile.frames[0].name.contains("input_profile"));
+        assert!(profile.frames[0].line > 0);
+        assert!(profile.frames[1].name.contains("getpid"));
+        assert_eq!(profile.threads[0].samples[0].frames, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_output_batch() {
+        let ip = input_profile as *const () as u64;
+        let batch = Outputter::new().output_batch(vec![
+            input_profile(&[ip, 0x10]),
+            input_profile(&[ip + 1, ip]),
+        ]);
+        assert_eq!(batch.version, FORMAT_VERSION);
+        assert_eq!(batch.modules.len(), 1);
+        assert_eq!(batch.frames.len(), 3);
+        assert_eq!(batch.profiles.len(), 2);
+        let frames = |profile: &BatchProfile| profile.threads[0].samples[0].frames.clone();
+        assert_eq!(frames(&batch.profiles[0]), vec![0, 1]);
+        assert_eq!(frames(&batch.profiles[1]), vec![2, 0]);
+
+        let serialized = serde_json::to_vec(&batch).unwrap();
+        let loaded = load_batch(&serialized[..]).expect("loaded");
+        assert_eq!(loaded.version, FORMAT_VERSION);
+        assert_eq!(loaded.modules, batch.modules);
+        assert_eq!(loaded.diagnostics, batch.diagnostics);
+        assert_eq!(loaded.frames, batch.frames);
+        assert_eq!(loaded.profiles.len(), 2);
+        assert_eq!(frames(&loaded.profiles[0]), vec![0, 1]);
+        assert_eq!(frames(&loaded.profiles[1]), vec![2, 0]);
+
+        // There are no batches of older versions to upgrade.
+        match load_batch(&br#"{"version": 1, "modules": [], "frames": [], "profiles": []}"#[..]) {
+            Err(LoadError::Malformed(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_future_version() {
+        let serialized = format!(
+            r#"{{"version": {}, "modules": [], "threads": [], "frames": []}}"#,
+            FORMAT_VERSION + 1
+        );
+        match load(serialized.as_bytes()) {
+            Err(LoadError::UnsupportedVersion(v)) => assert_eq!(v, u64::from(FORMAT_VERSION) + 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_load_malformed() {
+        match load(&b"[]"[..]) {
+            Err(LoadError::Malformed(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match load(&br#"{"version": "one"}"#[..]) {
+            Err(LoadError::Malformed(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
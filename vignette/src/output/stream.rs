@@ -0,0 +1,395 @@
+// Incremental version of the vignette format, for sessions too long to hold in memory.
+//
+// The stream is newline delimited JSON. The first record is a header with the format version,
+// followed by module, diagnostic, JIT symbol, frame and sample records in the order they were
+// discovered. JIT symbols belong to the JIT module, and are only attached to it by `read`.
+// Modules and frames are numbered implicitly by their position in the stream, so a sample only
+// ever refers to modules and frames written before it. Every record is written with a single
+// `write_all`, so a crash can only leave a truncated final line, which `read` ignores.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+};
+
+use super::{
+    compression::Decoder,
+    serde_json::{self, Map, Value},
+    upgrade, Diagnostic, Frame, JitSymbol, LoadError, Module, Outputter, Profile, ProfileKind,
+    FORMAT_VERSION,
+};
+use threadinfo::Thread as ThreadId;
+use Profile as InputProfile;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    Header {
+        version: u32,
+    },
+    Module(&'a Module),
+    Diagnostic(&'a Diagnostic),
+    JitSymbol(&'a JitSymbol),
+    Frame(&'a Frame),
+    Sample {
+        thread_id: ThreadId,
+        frames: &'a [usize],
+    },
+}
+
+/// Writes profiles to `W` as samples are converted, instead of building a whole `Profile`.
+///
+/// Call `write` with the output of `Session::drain` as often as required, then `finish` once the
+/// session is over. Use `read` to turn the stream back into a `Profile`.
+///
+/// To compress the stream, pass a `compression::Encoder` as `W`, and call `Encoder::finish` on
+/// the writer `finish` returns to complete the compressed stream.
+pub struct StreamWriter<W: Write> {
+    outputter: Outputter,
+    writer: W,
+    // Number of modules, diagnostics, JIT symbols and frames from the outputter indices already
+    // written to the stream.
+    modules_written: usize,
+    diagnostics_written: usize,
+    jit_symbols_written: usize,
+    frames_written: usize,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// Creates a new writer and writes the stream header.
+    pub fn new(writer: W) -> io::Result<Self> {
+        let mut stream = StreamWriter {
+            outputter: Outputter::new(),
+            writer,
+            modules_written: 0,
+            diagnostics_written: 0,
+            jit_symbols_written: 0,
+            frames_written: 0,
+            buf: Vec::new(),
+        };
+        stream.write_record(&Record::Header {
+            version: FORMAT_VERSION,
+        })?;
+        stream.writer.flush()?;
+        Ok(stream)
+    }
+
+    fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.buf.clear();
+        serde_json::to_writer(&mut self.buf, record)?;
+        self.buf.push(b'\n');
+        self.writer.write_all(&self.buf)
+    }
+
+    /// Converts and appends the samples in `profile`, along with any modules and frames they
+    /// refer to that have not been written yet. The underlying writer is flushed afterwards.
+    pub fn write(&mut self, profile: InputProfile) -> io::Result<()> {
+        for (thread_id, samples) in profile.threads {
+            for sample in samples {
+                let sample = self.outputter.output_sample(sample);
+
+                let new_modules: Vec<Module> = self.outputter.module_index.items()
+                    [self.modules_written..]
+                    .iter()
+                    .cloned()
+                    .map(Module::from)
+                    .collect();
+                for module in &new_modules {
+                    self.write_record(&Record::Module(module))?;
+                }
+                self.modules_written += new_modules.len();
+
+                let new_diagnostics =
+                    self.outputter.diagnostics_index.items()[self.diagnostics_written..].to_vec();
+                for diagnostic in &new_diagnostics {
+                    self.write_record(&Record::Diagnostic(diagnostic))?;
+                }
+                self.diagnostics_written += new_diagnostics.len();
+
+                let new_jit_symbols =
+                    self.outputter.jit_symbols.items()[self.jit_symbols_written..].to_vec();
+                for symbol in &new_jit_symbols {
+                    self.write_record(&Record::JitSymbol(symbol))?;
+                }
+                self.jit_symbols_written += new_jit_symbols.len();
+
+                let new_frames =
+                    self.outputter.frames_index.items()[self.frames_written..].to_vec();
+                for frame in &new_frames {
+                    self.write_record(&Record::Frame(frame))?;
+                }
+                self.frames_written += new_frames.len();
+
+                self.write_record(&Record::Sample {
+                    thread_id,
+                    frames: &sample.frames,
+                })?;
+            }
+        }
+        self.writer.flush()
+    }
+
+    /// Flushes and returns the underlying writer. This does not finish a `compression::Encoder`,
+    /// which the caller has to do.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reassembles a stream written by `StreamWriter` into a `Profile`.
+///
+/// A truncated final record, as left behind by a crash, is ignored so that the samples written
+/// before it can still be used. Streams written by older versions are upgraded, and compressed
+/// streams are decompressed, like `load` does.
+pub fn read<R: BufRead>(reader: R) -> Result<Profile, LoadError> {
+    let mut reader = BufReader::new(Decoder::new(reader)?);
+
+    let mut version = None;
+    let mut modules = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut jit_symbols = Vec::new();
+    let mut frames = Vec::new();
+    // Samples per thread, keeping threads in the order they first appeared.
+    let mut thread_ids = Vec::new();
+    let mut samples: HashMap<u64, Vec<Value>> = HashMap::new();
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            // A compressed stream cut short by a crash.
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                line.clear();
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let mut record: Map<String, Value> = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            // Only the last line can be incomplete.
+            Err(_) if !line.ends_with('\n') => break,
+            Err(e) => return Err(e.into()),
+        };
+
+        let record_type = match record.remove("type") {
+            Some(Value::String(record_type)) => record_type,
+            _ => return Err(LoadError::Malformed("stream record without a type")),
+        };
+        if version.is_none() {
+            if record_type != "header" {
+                return Err(LoadError::Malformed("stream does not start with a header"));
+            }
+            version = Some(
+                record
+                    .remove("version")
+                    .ok_or(LoadError::Malformed("stream header without a version"))?,
+            );
+            continue;
+        }
+
+        match record_type.as_str() {
+            "module" => modules.push(Value::Object(record)),
+            "diagnostic" => diagnostics.push(Value::Object(record)),
+            "jit_symbol" => jit_symbols.push(Value::Object(record)),
+            "frame" => frames.push(Value::Object(record)),
+            "sample" => {
+                let thread_id = record
+                    .remove("thread_id")
+                    .and_then(|id| id.as_u64())
+                    .ok_or(LoadError::Malformed("sample without a thread_id"))?;
+                let thread_samples = samples.entry(thread_id).or_insert_with(|| {
+                    thread_ids.push(thread_id);
+                    Vec::new()
+                });
+                thread_samples.push(Value::Object(record));
+            }
+            _ => return Err(LoadError::Malformed("unknown stream record type")),
+        }
+    }
+
+    let threads = thread_ids
+        .into_iter()
+        .map(|thread_id| {
+            let mut thread = Map::new();
+            thread.insert("thread_id".to_owned(), Value::from(thread_id));
+            thread.insert(
+                "samples".to_owned(),
+                Value::Array(samples.remove(&thread_id).unwrap_or_default()),
+            );
+            Value::Object(thread)
+        })
+        .collect();
+
+    // Sorted by address, like `Outputter::output` does.
+    jit_symbols.sort_by_key(|symbol| symbol.get("address").and_then(|a| a.as_u64()));
+    for module in &mut modules {
+        if module.get("id_kind").and_then(|kind| kind.as_str()) == Some("jit") {
+            module["jit_symbols"] = Value::Array(jit_symbols.clone());
+        }
+    }
+
+    let mut profile = Map::new();
+    profile.insert(
+        "version".to_owned(),
+        version.ok_or(LoadError::Malformed("empty stream"))?,
+    );
+    profile.insert("modules".to_owned(), Value::Array(modules));
+    profile.insert("diagnostics".to_owned(), Value::Array(diagnostics));
+    profile.insert("threads".to_owned(), Value::Array(threads));
+    profile.insert("frames".to_owned(), Value::Array(frames));
+
+    let value = upgrade(Value::Object(profile), ProfileKind::Unresolved)?;
+    Ok(serde_json::from_value(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jit::JitCache;
+    use module_cache::ModuleCache;
+    use output::IdKind;
+    use std::collections::HashMap;
+    use threadinfo;
+    use types::Frame as InputFrame;
+    use RecordedSample;
+
+    fn input_profile(samples: usize) -> InputProfile {
+        let ip = input_profile as *const () as u64;
+        let modules = ModuleCache::new().snapshot();
+        let jit = JitCache::new().snapshot();
+        let mut threads = HashMap::new();
+        threads.insert(
+            threadinfo::current_thread().unwrap(),
+            (0..samples)
+                .map(|i| RecordedSample {
+                    modules: modules.clone(),
+                    jit: jit.clone(),
+                    frames: vec![
+                        InputFrame { ip: ip + i as u64 },
+                        InputFrame { ip: ip + 1 },
+                        InputFrame { ip },
+                    ],
+                })
+                .collect(),
+        );
+        InputProfile { threads }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut writer = StreamWriter::new(Vec::new()).unwrap();
+        writer.write(input_profile(2)).unwrap();
+        writer.write(input_profile(3)).unwrap();
+        let stream = writer.finish().unwrap();
+
+        let profile = read(&stream[..]).expect("valid stream");
+        assert_eq!(profile.version, FORMAT_VERSION);
+        assert_eq!(profile.modules.len(), 1);
+        assert_eq!(profile.threads.len(), 1);
+        assert_eq!(profile.threads[0].samples.len(), 5);
+        // ip, ip + 1 and ip + 2 are the only distinct frames.
+        assert_eq!(profile.frames.len(), 3);
+        // All of them are in input_profile, which starts at ip.
+        let start = profile
+            .frames
+            .iter()
+            .map(|frame| frame.relative_ip)
+            .min()
+            .unwrap();
+        assert!(profile
+            .frames
+            .iter()
+            .all(|frame| frame.function_rva == Some(start)));
+        for sample in &profile.threads[0].samples {
+            assert_eq!(sample.frames.len(), 3);
+            assert!(sample.frames.iter().all(|&f| f < profile.frames.len()));
+        }
+    }
+
+    #[test]
+    fn test_jit_symbols() {
+        ::register_jit_region(0x40_0000, 0x100, "streamed");
+        let mut profile = input_profile(1);
+        for samples in profile.threads.values_mut() {
+            samples[0].jit = JitCache::new().snapshot();
+            samples[0].frames.push(InputFrame { ip: 0x40_0020 });
+        }
+        let mut writer = StreamWriter::new(Vec::new()).unwrap();
+        writer.write(profile).unwrap();
+        let stream = writer.finish().unwrap();
+
+        let profile = read(&stream[..]).expect("valid stream");
+        let jit = profile
+            .modules
+            .iter()
+            .find(|module| module.id_kind == IdKind::Jit)
+            .expect("JIT module");
+        let symbols = jit.jit_symbols.as_ref().expect("JIT symbols");
+        assert_eq!(symbols.len(), 1);
+        assert_eq!(symbols[0].name, "streamed");
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut writer = StreamWriter::new(Vec::new()).unwrap();
+        writer.write(input_profile(2)).unwrap();
+        let mut stream = writer.finish().unwrap();
+        // Simulate a crash while writing the last sample record.
+        let len = stream.len();
+        stream.truncate(len - 5);
+
+        let profile = read(&stream[..]).expect("valid stream");
+        assert_eq!(profile.threads[0].samples.len(), 1);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_truncated_compressed() {
+        use output::compression::{Compression, Encoder};
+
+        let encoder = Encoder::new(Vec::new(), Compression::Gzip).unwrap();
+        let mut writer = StreamWriter::new(encoder).unwrap();
+        writer.write(input_profile(2)).unwrap();
+        writer.write(input_profile(2)).unwrap();
+        // Simulate a crash before the end of the gzip stream was written.
+        let stream = writer.finish().unwrap().get_ref().clone();
+
+        let profile = read(&stream[..]).expect("valid stream");
+        assert_eq!(profile.threads[0].samples.len(), 4);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed() {
+        use output::compression::{Compression, Encoder};
+
+        let encoder = Encoder::new(Vec::new(), Compression::Zstd).unwrap();
+        let mut writer = StreamWriter::new(encoder).unwrap();
+        writer.write(input_profile(2)).unwrap();
+        let stream = writer.finish().unwrap().finish().unwrap();
+
+        let profile = read(&stream[..]).expect("valid stream");
+        assert_eq!(profile.threads[0].samples.len(), 2);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let stream = format!(
+            "{{\"type\":\"header\",\"version\":{}}}\nnot json\n",
+            FORMAT_VERSION
+        );
+        match read(stream.as_bytes()) {
+            Err(LoadError::Json(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match read(&b""[..]) {
+            Err(LoadError::Malformed(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
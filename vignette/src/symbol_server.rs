@@ -0,0 +1,168 @@
+// Client for breakpad symbol servers, which serve symbol files as `<name>/<debug id>/<name>.sym`.
+//
+// Downloads are kept in a cache on disk in the same layout, so the cache can also be passed to the
+// resolver as a search root. Symbol files that no server has are remembered too, so that every
+// run does not ask again for the symbols of system libraries nobody uploaded.
+
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use download::{self, Options};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a symbol file that no server had is not asked for again. Symbols are often uploaded
+// after the profiles that need them arrive.
+const MISSING_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// The largest symbol file we download. Those of the largest binaries are several hundred MB.
+const MAX_SIZE: u64 = 2 << 30;
+
+/// Fetches symbol files from symbol servers, and keeps them in a cache on disk.
+#[derive(Debug, Clone)]
+pub struct Client {
+    urls: Vec<String>,
+    cache_dir: PathBuf,
+    timeout: Duration,
+}
+
+impl Client {
+    /// Creates a client for the servers in `urls`, which are tried in order, caching files under
+    /// `cache_dir`.
+    pub fn new(urls: Vec<String>, cache_dir: PathBuf) -> Self {
+        Client {
+            urls: urls
+                .into_iter()
+                .map(|url| url.trim_end_matches('/').to_owned())
+                .collect(),
+            cache_dir,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Creates a client for the servers in `urls`, caching files in VIGNETTE_SYMBOL_CACHE, or else
+    /// in `vignette/symbols` under the user's cache directory. Returns None if there are no
+    /// servers.
+    pub fn with_default_cache(urls: Vec<String>) -> Option<Self> {
+        if urls.is_empty() {
+            return None;
+        }
+        let cache_dir = match env::var_os("VIGNETTE_SYMBOL_CACHE") {
+            Some(path) => PathBuf::from(path),
+            None => match env::var_os("XDG_CACHE_HOME") {
+                Some(path) => PathBuf::from(path),
+                None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+            }
+            .join("vignette")
+            .join("symbols"),
+        };
+        Some(Client::new(urls, cache_dir))
+    }
+
+    /// Returns the path of the symbol file for the module `name` with `debug_id`, downloading it
+    /// if it is not cached yet. Returns None if no server has it, and an error if a server could
+    /// not be asked and none of the others had it.
+    pub fn fetch(&self, name: &str, debug_id: &str) -> io::Result<Option<PathBuf>> {
+        // Both end up in a path, so they must not be able to escape the cache.
+        if Path::new(name).file_name().and_then(|name| name.to_str()) != Some(name) {
+            return Err(invalid(format!("invalid module name {}", name)));
+        }
+        if debug_id.is_empty() || !debug_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid(format!("invalid debug ID {}", debug_id)));
+        }
+        let sym_name = format!("{}.sym", name);
+        let urls: Vec<String> = self
+            .urls
+            .iter()
+            .map(|url| format!("{}/{}/{}/{}", url, name, debug_id, sym_name))
+            .collect();
+        let options = Options {
+            timeout: self.timeout,
+            max_size: MAX_SIZE,
+            missing_ttl: MISSING_TTL,
+        };
+        let path = self.cache_dir.join(name).join(debug_id).join(&sym_name);
+        download::fetch(path, &urls, &options)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use download::fixtures::{cache_dir, serve};
+    use std::fs;
+    use std::sync::atomic::Ordering;
+
+    const DEBUG_ID: &str = "0123456789ABCDEF0123456789ABCDEF0";
+
+    // Serves the symbols of libfoo.so with DEBUG_ID under /symbols, fails for libbroken.so, and
+    // 404s everything else.
+    fn respond(request: &str) -> String {
+        let found = format!("GET /symbols/libfoo.so/{}/libfoo.so.sym ", DEBUG_ID);
+        if request.starts_with(&found) {
+            "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nMODULE".to_owned()
+        } else if request.starts_with("GET /symbols/libbroken.so/") {
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".to_owned()
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_owned()
+        }
+    }
+
+    #[test]
+    fn test_fetch_and_cache() {
+        let (url, requests) = serve(respond);
+        let url = format!("{}/symbols/", url);
+        let cache_dir = cache_dir("symbols-fetch");
+        let client = Client::new(vec![url], cache_dir.clone());
+
+        let path = client.fetch("libfoo.so", DEBUG_ID).unwrap().unwrap();
+        assert_eq!(
+            path,
+            cache_dir
+                .join("libfoo.so")
+                .join(DEBUG_ID)
+                .join("libfoo.so.sym")
+        );
+        assert_eq!(fs::read(&path).unwrap(), b"MODULE");
+        assert_eq!(client.fetch("libfoo.so", DEBUG_ID).unwrap(), Some(path));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Missing symbols are only asked for once.
+        assert_eq!(client.fetch("libbar.so", DEBUG_ID).unwrap(), None);
+        assert_eq!(client.fetch("libbar.so", DEBUG_ID).unwrap(), None);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        // Failures are reported, and asked again.
+        assert!(client.fetch("libbroken.so", DEBUG_ID).is_err());
+        assert!(client.fetch("libbroken.so", DEBUG_ID).is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+
+        assert!(client.fetch("../libfoo.so", DEBUG_ID).is_err());
+        assert!(client.fetch("libfoo.so", "../x").is_err());
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_unreachable_server() {
+        let (url, _) = serve(respond);
+        let url = format!("{}/symbols/", url);
+        let cache_dir = cache_dir("symbols-unreachable");
+        // Nothing listens on port 1.
+        let unreachable = "http://127.0.0.1:1".to_owned();
+        let client = Client::new(vec![unreachable.clone(), url], cache_dir.clone());
+        assert!(client.fetch("libfoo.so", DEBUG_ID).unwrap().is_some());
+        // The unreachable server might have had it.
+        assert!(client.fetch("libbar.so", DEBUG_ID).is_err());
+
+        let client = Client::new(vec![unreachable], cache_dir.clone());
+        assert!(client.fetch("libbaz.so", DEBUG_ID).is_err());
+        assert!(!cache_dir.join("libbaz.so").exists());
+        fs::remove_dir_all(cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,114 @@
+// Symcaches converted by earlier symbolizers, which are much faster to memory-map than converting
+// large symbol files again.
+//
+// They are stored as `<name>/<debug id>/<name>.symcache`, with the versions and vmaddr they were
+// written with in a `.meta` file next to them. Only symbols with line information are stored:
+// those without come from symbol tables, and would otherwise hide debug information that a
+// source gains later, e.g. once a debug package is installed, since the cache is asked first.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{
+    symbolic_common::byteview::ByteView,
+    symbolic_symcache::{SymCache, SYMCACHE_LATEST_VERSION},
+    Error, Module, ModuleSymbols,
+};
+
+// Version of the cache layout. Bump it when symcaches are built differently, so that stale ones
+// are rebuilt.
+const DISK_CACHE_VERSION: u32 = 2;
+
+// Counts the temporary files this process wrote, to give each a name of its own.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf) -> Self {
+        DiskCache { dir }
+    }
+
+    // The symcache and meta file of `module`, if its name and debug ID can be used in a path.
+    fn paths(&self, module: &Module) -> Option<(PathBuf, PathBuf)> {
+        if Path::new(&module.name).file_name() != Some(OsStr::new(&module.name))
+            || module.debug_id.is_empty()
+            || !module.debug_id.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return None;
+        }
+        let dir = self.dir.join(&module.name).join(&module.debug_id);
+        Some((
+            dir.join(format!("{}.symcache", module.name)),
+            dir.join(format!("{}.symcache.meta", module.name)),
+        ))
+    }
+
+    // The meta file's contents for a symcache of a module at `vmaddr`.
+    fn meta(vmaddr: u64) -> String {
+        format!(
+            "{} {} {:x}\n",
+            DISK_CACHE_VERSION, SYMCACHE_LATEST_VERSION, vmaddr
+        )
+    }
+
+    // Memory-maps the symcache of `module`, if one was written by a compatible version. A symcache
+    // that cannot be read is an error, and gets replaced once the symbols are found again.
+    pub fn load(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+        let (path, meta_path) = match self.paths(module) {
+            Some(paths) => paths,
+            None => return Ok(None),
+        };
+        let meta = match fs::read_to_string(&meta_path) {
+            Ok(meta) => meta,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let vmaddr = meta
+            .trim_end()
+            .rsplit(' ')
+            .next()
+            .and_then(|vmaddr| u64::from_str_radix(vmaddr, 16).ok());
+        let vmaddr = match vmaddr {
+            Some(vmaddr) if meta == DiskCache::meta(vmaddr) => vmaddr,
+            _ => return Ok(None),
+        };
+        let cache = SymCache::parse(ByteView::from_path(&path)?)
+            .map_err(|e| Error::InvalidSymbols(format!("{}: {}", path.display(), e)))?;
+        Ok(Some(ModuleSymbols { cache, vmaddr }))
+    }
+
+    // Stores the symbols of `module`, unless they lack line information.
+    pub fn store(&self, module: &Module, symbols: &ModuleSymbols) -> io::Result<()> {
+        if !symbols.cache.has_line_info().unwrap_or(false) {
+            return Ok(());
+        }
+        let (path, meta_path) = match self.paths(module) {
+            Some(paths) => paths,
+            None => return Ok(()),
+        };
+        let dir = path.parent().expect("symcache in a directory");
+        fs::create_dir_all(dir)?;
+        // Written under temporary names, so that a concurrent or interrupted run never sees a
+        // partial symcache. The meta file goes last, since nothing is loaded without it. The names
+        // are unique to this call, since threads of one process may store the same module.
+        let partial = dir.join(format!(
+            ".{}.{}.{}",
+            module.name,
+            process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        symbols.cache.to_writer(File::create(&partial)?)?;
+        fs::rename(&partial, &path)?;
+        fs::write(&partial, DiskCache::meta(symbols.vmaddr))?;
+        fs::rename(&partial, &meta_path)
+    }
+}
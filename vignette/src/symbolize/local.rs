@@ -0,0 +1,334 @@
+// Symbols in a directory: a breakpad symbol store, a tree of separate debug files, or unstripped
+// binaries.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{
+    parse_file,
+    symbolic_common::{byteview::ByteView, types::DebugKind},
+    symbolic_debuginfo::FatObject,
+    Error, IdKind, Module, ModuleSymbols, SymbolSource,
+};
+
+// An ELF file found by scanning a directory.
+struct DebugFile {
+    path: PathBuf,
+    has_dwarf: bool,
+}
+
+/// A directory to look for symbols in.
+///
+/// For each module, it looks for a breakpad symbol file, then for a debug file in the
+/// `.build-id/xx/yyyy.debug` layout of distro debug packages, then for the file named by the
+/// module's `.gnu_debuglink`. Debug files found by name are only used if they match the module.
+pub struct LocalDir {
+    path: PathBuf,
+    scan: bool,
+    // The ELF files under `path` by breakpad debug ID, which is derived from the build ID. Only
+    // built once a module needs it. The scan runs without holding the lock, so threads that race
+    // may each build it, and the first to finish is kept.
+    debug_files: Mutex<Option<HashMap<String, DebugFile>>>,
+}
+
+impl LocalDir {
+    /// Looks for symbols under `path`. With `scan`, every ELF file under `path` is also indexed
+    /// by build ID, whatever its name, which is too slow for large directories like
+    /// /usr/lib/debug.
+    pub fn new(path: PathBuf, scan: bool) -> Self {
+        LocalDir {
+            path,
+            scan,
+            debug_files: Mutex::new(None),
+        }
+    }
+}
+
+impl SymbolSource for LocalDir {
+    fn find(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+        let mut candidates = layout_paths(&self.path, module);
+        if self.scan {
+            let indexed = self.debug_files.lock().unwrap().is_some();
+            if !indexed {
+                let mut files = HashMap::new();
+                index_debug_files(&self.path, &mut files);
+                self.debug_files.lock().unwrap().get_or_insert(files);
+            }
+            let debug_files = self.debug_files.lock().unwrap();
+            let file = debug_files
+                .as_ref()
+                .and_then(|files| files.get(&module.debug_id));
+            if let Some(file) = file {
+                candidates.push(file.path.clone());
+            }
+        }
+
+        // Files that are not there are expected, including when a path leads through a file, like
+        // an unstripped binary with the module's name. Others that cannot be used only matter if
+        // no other file can.
+        let mut error = None;
+        for candidate in candidates {
+            let contents = match fs::read(&candidate) {
+                Ok(contents) => contents,
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::NotFound
+                        || e.kind() == io::ErrorKind::NotADirectory =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    error = Some(Error::Io(e));
+                    continue;
+                }
+            };
+            match ModuleSymbols::parse(&contents, &candidate.display().to_string(), module) {
+                Ok(symbols) => return Ok(Some(symbols)),
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The files modules were loaded from, at the paths the profile recorded. For a profile of the
+/// running process, these are its own binaries and libraries.
+///
+/// Files that were rebuilt since are detected by their identifiers and not used.
+#[derive(Debug, Default)]
+pub struct ModuleFiles;
+
+impl ModuleFiles {
+    pub fn new() -> Self {
+        ModuleFiles
+    }
+}
+
+impl SymbolSource for ModuleFiles {
+    fn find(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+        let path = match module.path {
+            Some(ref path) => Path::new(path),
+            None => return Ok(None),
+        };
+        match parse_file(path, module) {
+            Ok(symbols) => Ok(Some(symbols)),
+            // Modules without a file, like the vDSO, are reported by name.
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Adds the ELF files under `dir` to `files`. Unreadable entries are skipped.
+fn index_debug_files(dir: &Path, files: &mut HashMap<String, DebugFile>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            index_debug_files(&path, files);
+            continue;
+        }
+
+        // Check the magic before reading the whole file.
+        let mut magic = [0; 4];
+        let is_elf = File::open(&path)
+            .and_then(|mut file| file.read_exact(&mut magic))
+            .is_ok()
+            && &magic == b"\x7fELF";
+        if !is_elf {
+            continue;
+        }
+        let bytes = match ByteView::from_path(&path) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        let fat_object = match FatObject::parse(bytes) {
+            Ok(fat_object) => fat_object,
+            Err(_) => continue,
+        };
+        let object = match fat_object.get_object(0) {
+            Ok(Some(object)) => object,
+            _ => continue,
+        };
+        let debug_id = match object.id() {
+            Some(id) => id.breakpad().to_string(),
+            None => continue,
+        };
+        let has_dwarf = object.debug_kind() == Some(DebugKind::Dwarf);
+        // A binary and its separate debug file share a build ID. Prefer whichever has DWARF.
+        let replace = match files.get(&debug_id) {
+            Some(known) => has_dwarf && !known.has_dwarf,
+            None => true,
+        };
+        if replace {
+            files.insert(debug_id, DebugFile { path, has_dwarf });
+        }
+    }
+}
+
+// Whether `name` is a plain file name, which can be joined onto a directory without leading out
+// of it.
+fn is_file_name(name: &str) -> bool {
+    Path::new(name).file_name() == Some(OsStr::new(name))
+}
+
+// The paths `module`'s symbols would have under `root` in the layouts we know about, in the order
+// they are tried. Layouts the module's identifiers or file names can't be used in safely are
+// skipped.
+fn layout_paths(root: &Path, module: &Module) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    // A breakpad symbol store, then the code ID layout older versions of generate_symbols used.
+    if is_file_name(&module.name) {
+        for id in &[&module.debug_id, &module.code_id] {
+            if is_file_name(id) {
+                paths.push(
+                    root.join(&module.name)
+                        .join(id)
+                        .join(format!("{}.sym", module.name)),
+                );
+            }
+        }
+    }
+    // The layout of distro debug packages, keyed on the full build ID.
+    if module.id_kind == IdKind::BuildId
+        && module.code_id.len() > 2
+        && module.code_id.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        let build_id = module.code_id.to_lowercase();
+        paths.push(
+            root.join(".build-id")
+                .join(&build_id[..2])
+                .join(format!("{}.debug", &build_id[2..])),
+        );
+    }
+    // Separate debug files named by .gnu_debuglink, under the directory the module was loaded from
+    // like gdb looks for them, or directly in the root.
+    if let Some(ref debug_file) = module.debug_file {
+        if is_file_name(debug_file) {
+            let dir = module
+                .path
+                .as_ref()
+                .and_then(|path| Path::new(path).parent())
+                .map(|dir| dir.strip_prefix("/").unwrap_or(dir))
+                // Only directory names, no `..` that would lead out of the root.
+                .filter(|dir| {
+                    dir.components()
+                        .all(|component| matches!(component, Component::Normal(_)))
+                });
+            if let Some(dir) = dir {
+                paths.push(root.join(dir).join(debug_file));
+            }
+            paths.push(root.join(debug_file));
+        }
+    }
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    fn module() -> Module {
+        Module {
+            name: "libfoo.so".to_owned(),
+            code_id: "ABCDEF".to_owned(),
+            debug_id: "EFCDAB0000000000000000000000000000".to_owned(),
+            id_kind: IdKind::BuildId,
+            path: Some("/usr/lib/libfoo.so".to_owned()),
+            base_address: None,
+            size: None,
+            image_vaddr: None,
+            arch: None,
+            soname: None,
+            debug_file: Some("libfoo.so.debug".to_owned()),
+            jit_symbols: None,
+        }
+    }
+
+    #[test]
+    fn test_layout_paths() {
+        let module = module();
+        let root = Path::new("/symbols");
+        assert_eq!(
+            layout_paths(root, &module),
+            vec![
+                root.join("libfoo.so/EFCDAB0000000000000000000000000000/libfoo.so.sym"),
+                root.join("libfoo.so/ABCDEF/libfoo.so.sym"),
+                root.join(".build-id/ab/cdef.debug"),
+                root.join("usr/lib/libfoo.so.debug"),
+                root.join("libfoo.so.debug"),
+            ]
+        );
+
+        // Debug file names must not lead out of the root.
+        let escaping = Module {
+            id_kind: IdKind::TextHash,
+            debug_file: Some("../libfoo.so.debug".to_owned()),
+            ..module.clone()
+        };
+        assert_eq!(layout_paths(root, &escaping).len(), 2);
+
+        // Nor may any other part of the module.
+        let escaping = Module {
+            name: "../libfoo.so".to_owned(),
+            code_id: "AB/../CDEF".to_owned(),
+            path: Some("/usr/../../lib/libfoo.so".to_owned()),
+            ..module.clone()
+        };
+        assert_eq!(
+            layout_paths(root, &escaping),
+            vec![root.join("libfoo.so.debug")]
+        );
+        let escaping = Module {
+            debug_id: "..".to_owned(),
+            // Not ASCII, so not a build ID to slice either.
+            code_id: "\u{e9}/..".to_owned(),
+            ..module
+        };
+        assert_eq!(
+            layout_paths(root, &escaping),
+            vec![
+                root.join("usr/lib/libfoo.so.debug"),
+                root.join("libfoo.so.debug"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_errors() {
+        let dir = env::temp_dir().join(format!("vignette-local-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("libfoo.so.debug")).unwrap();
+        // The breakpad layout paths lead through this file.
+        fs::write(dir.join("libfoo.so"), b"not a directory").unwrap();
+        let source = LocalDir::new(dir.clone(), false);
+
+        // The debug file is a directory, which is an error rather than missing.
+        match source.find(&module()) {
+            Err(Error::Io(_)) => {}
+            other => panic!("expected an I/O error, got {:?}", other.map(|_| ())),
+        }
+        let no_debug_file = Module {
+            debug_file: None,
+            ..module()
+        };
+        assert!(source.find(&no_debug_file).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
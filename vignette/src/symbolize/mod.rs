@@ -0,0 +1,945 @@
+// Symbolication of profiles: turning the module-relative instruction pointers of a `Profile` into
+// function names, files and lines.
+//
+// Symbols come from any number of `SymbolSource`s, which are asked in order. The sources in this
+// module cover directories of symbol files, symbol servers, debuginfod and symbol files held in
+// memory, and others can be added by implementing the trait.
+
+extern crate symbolic_common;
+extern crate symbolic_debuginfo;
+extern crate symbolic_symcache;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, error, fmt, fs, io, panic,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+};
+
+use self::{
+    cache::DiskCache,
+    symbolic_common::{byteview::ByteView, types::ObjectKind},
+    symbolic_debuginfo::{FatObject, Object},
+    symbolic_symcache::SymCache,
+};
+use super::{
+    debuginfod,
+    output::{
+        Frame, IdKind, InlinedFrame, JitSymbol, Module, Profile, ResolvedFrame, ResolvedProfile,
+        FORMAT_VERSION,
+    },
+    symbol_server,
+};
+
+mod cache;
+mod local;
+pub mod server;
+mod symtab;
+
+pub use self::local::{LocalDir, ModuleFiles};
+
+// Where distros install the contents of debug packages.
+const SYSTEM_DEBUG_DIR: &str = "/usr/lib/debug";
+
+/// Errors from symbolicating a profile or from loading symbols.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A file could not be used as symbols, with a description of the file and why.
+    InvalidSymbols(String),
+    /// A debug file found by name was built from a different build of the module than the one
+    /// profiled, with a description of the file.
+    Mismatch(String),
+    /// The profile refers to modules or frames it does not have.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::InvalidSymbols(ref reason) => write!(f, "invalid symbols: {}", reason),
+            Error::Mismatch(ref origin) => write!(f, "{}: does not match the module", origin),
+            Error::Malformed(reason) => write!(f, "malformed profile: {}", reason),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// The symbols of a module, ready for lookups.
+pub struct ModuleSymbols {
+    cache: SymCache<'static>,
+    // The address the module's addresses are relative to in `cache`, since relative IPs are the
+    // addresses in the ELF file. Caches built from ELF files are relative to the lowest mapped
+    // address, and breakpad symbol files to the module's image address. Neither is 0 for non-PIE
+    // executables.
+    vmaddr: u64,
+}
+
+impl ModuleSymbols {
+    /// Parses a breakpad symbol file or an ELF file, with or without DWARF, as the symbols of
+    /// `module`. ELF files without DWARF only resolve functions, from their `.symtab` or else their
+    /// `.dynsym`. ELF files are checked against the module's identifier, so that the symbols of
+    /// another build of the same library are never used. `origin` describes where `contents` came
+    /// from, for errors.
+    pub fn parse(contents: &[u8], origin: &str, module: &Module) -> Result<Self, Error> {
+        let invalid = |reason: String| Error::InvalidSymbols(format!("{}: {}", origin, reason));
+        let fat_object =
+            FatObject::parse(ByteView::from_slice(contents)).map_err(|e| invalid(e.to_string()))?;
+        match fat_object.kind() {
+            ObjectKind::Breakpad | ObjectKind::Elf => {}
+            kind => return Err(invalid(format!("unsupported {:?}", kind))),
+        }
+        if fat_object.object_count() != 1 {
+            return Err(invalid(format!("{} objects", fat_object.object_count())));
+        }
+        let object = match fat_object.get_object(0) {
+            Ok(Some(object)) => object,
+            Ok(None) => return Err(invalid("no object".to_owned())),
+            Err(e) => return Err(invalid(e.to_string())),
+        };
+        if object.kind() == ObjectKind::Elf && !matches(module, &object, contents) {
+            return Err(Error::Mismatch(origin.to_owned()));
+        }
+        let cache = if object.kind() == ObjectKind::Elf && object.debug_kind().is_none() {
+            // Without DWARF there are only the function names in the symbol tables.
+            let arch = object
+                .arch()
+                .map_err(|e| invalid(e.to_string()))?
+                .to_breakpad();
+            let symbols = symtab::breakpad_symbols(contents, &module.name, arch, object.vmaddr())
+                .ok_or_else(|| invalid("no debug information or symbols".to_owned()))?;
+            let fat_object = FatObject::parse(ByteView::from_slice(symbols.as_bytes()))
+                .map_err(|e| invalid(e.to_string()))?;
+            match fat_object.get_object(0) {
+                Ok(Some(object)) => SymCache::from_object(&object),
+                Ok(None) => return Err(invalid("no object".to_owned())),
+                Err(e) => return Err(invalid(e.to_string())),
+            }
+        } else {
+            SymCache::from_object(&object)
+        }
+        .map_err(|e| invalid(e.to_string()))?;
+        let vmaddr = match object.kind() {
+            ObjectKind::Breakpad => module.image_vaddr.unwrap_or(0),
+            _ => object.vmaddr(),
+        };
+        Ok(ModuleSymbols { cache, vmaddr })
+    }
+
+    /// Returns the function at `relative_ip`, along with the calls inlined into it there.
+    pub fn lookup(&self, relative_ip: u64) -> Option<ResolvedFrame> {
+        // Innermost first, the last one being the function that was not inlined.
+        let mut line_infos = self
+            .cache
+            .lookup(relative_ip.wrapping_sub(self.vmaddr))
+            .ok()?;
+        let outermost = line_infos.pop()?;
+        Some(ResolvedFrame {
+            name: outermost.function_name(),
+            file: outermost.filename().to_owned(),
+            line: outermost.line(),
+            inlined: line_infos
+                .iter()
+                .map(|x| InlinedFrame {
+                    name: x.function_name(),
+                    file: x.filename().to_owned(),
+                    line: x.line(),
+                })
+                .collect(),
+        })
+    }
+}
+
+// The CRC32 used by .gnu_debuglink, which is the common IEEE one.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Whether an ELF file is the one `module` was built as, rather than another build of the same
+// library.
+fn matches(module: &Module, object: &Object, contents: &[u8]) -> bool {
+    match module.id_kind {
+        IdKind::BuildId | IdKind::TextHash => {
+            object.id().map(|id| id.breakpad().to_string()).as_ref() == Some(&module.debug_id)
+        }
+        IdKind::DebuglinkCrc => {
+            u32::from_str_radix(&module.code_id, 16).ok() == Some(crc32(contents))
+        }
+        // Nothing to compare.
+        IdKind::MtimeSize | IdKind::Jit => true,
+    }
+}
+
+/// Somewhere to get the symbols of modules from.
+///
+/// Sources are shared by the threads of a `Symbolizer`, each of which asks for different modules.
+pub trait SymbolSource: Send + Sync {
+    /// Returns the symbols of `module`, or None if this source does not have them.
+    fn find(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error>;
+}
+
+// Parses the file at `path`, which a source found for `module`.
+fn parse_file(path: &Path, module: &Module) -> Result<ModuleSymbols, Error> {
+    let contents = fs::read(path)?;
+    ModuleSymbols::parse(&contents, &path.display().to_string(), module)
+}
+
+/// Asks debuginfod for the debug file or, failing that, the binary of modules with a GNU build ID.
+impl SymbolSource for debuginfod::Client {
+    fn find(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+        if module.id_kind != IdKind::BuildId {
+            return Ok(None);
+        }
+        let path = match self.debuginfo(&module.code_id)? {
+            Some(path) => path,
+            None => match self.executable(&module.code_id)? {
+                Some(path) => path,
+                None => return Ok(None),
+            },
+        };
+        parse_file(&path, module).map(Some)
+    }
+}
+
+/// Asks breakpad symbol servers for symbol files.
+impl SymbolSource for symbol_server::Client {
+    fn find(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+        match self.fetch(&module.name, &module.debug_id)? {
+            Some(path) => parse_file(&path, module).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Symbol files held in memory, e.g. as received by a service, by breakpad debug ID.
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        MemorySource::default()
+    }
+
+    /// Adds the contents of a breakpad symbol file or ELF file for the module with `debug_id`.
+    pub fn insert(&mut self, debug_id: String, contents: Vec<u8>) {
+        self.files.insert(debug_id, contents);
+    }
+}
+
+impl SymbolSource for MemorySource {
+    fn find(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+        match self.files.get(&module.debug_id) {
+            Some(contents) => {
+                let origin = format!("symbols of {} in memory", module.name);
+                ModuleSymbols::parse(contents, &origin, module).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Something that went wrong getting the symbols of a module.
+#[derive(Debug)]
+pub struct Problem {
+    /// Index into the profile's modules.
+    pub module_index: u32,
+    /// Whether symbols were found anyway, e.g. by a source after the one that failed.
+    pub resolved: bool,
+    /// The errors of failing sources and of the symbol cache, in the order they happened. Empty
+    /// if no source had the module's symbols.
+    pub errors: Vec<Error>,
+}
+
+/// A profile with its frames resolved.
+#[derive(Debug)]
+pub struct Symbolized {
+    pub profile: ResolvedProfile,
+    /// Problems getting the symbols of modules, by module index. Frames in modules that were not
+    /// resolved are named "unknown".
+    pub problems: Vec<Problem>,
+}
+
+// The frames one thread resolved, with their indices, and the problems it ran into.
+type WorkerResult = (Vec<(usize, ResolvedFrame)>, Vec<Problem>);
+
+// A module whose symbols one thread is loading. Other threads that need them wait for its result
+// instead of loading them again.
+#[derive(Default)]
+struct Loading {
+    // None until the loading thread is done.
+    result: Mutex<Option<Option<Arc<ModuleSymbols>>>>,
+    done: Condvar,
+}
+
+impl Loading {
+    fn wait(&self) -> Option<Arc<ModuleSymbols>> {
+        let mut result = self.result.lock().unwrap();
+        loop {
+            if let Some(ref symbols) = *result {
+                return symbols.clone();
+            }
+            result = self.done.wait(result).unwrap();
+        }
+    }
+
+    fn finish(&self, symbols: Option<Arc<ModuleSymbols>>) {
+        *self.result.lock().unwrap() = Some(symbols);
+        self.done.notify_all();
+    }
+}
+
+// Symbols kept loaded between `Symbolizer::symbolize` calls, by module name and debug ID. The
+// least recently used ones are dropped first.
+struct MemoryCache {
+    capacity: usize,
+    // Counts lookups, to tell which symbols were used last.
+    clock: u64,
+    symbols: HashMap<(String, String), (Arc<ModuleSymbols>, u64)>,
+    // The modules being loaded right now.
+    loading: HashMap<(String, String), Arc<Loading>>,
+}
+
+impl MemoryCache {
+    fn get(&mut self, module: &Module) -> Option<Arc<ModuleSymbols>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let key = (module.name.clone(), module.debug_id.clone());
+        self.symbols.get_mut(&key).map(|entry| {
+            entry.1 = clock;
+            entry.0.clone()
+        })
+    }
+
+    fn insert(&mut self, module: &Module, symbols: Arc<ModuleSymbols>) {
+        if self.symbols.len() >= self.capacity {
+            let oldest = self
+                .symbols
+                .iter()
+                .min_by_key(|&(_, &(_, last_used))| last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.symbols.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        let key = (module.name.clone(), module.debug_id.clone());
+        self.symbols.insert(key, (symbols, self.clock));
+    }
+}
+
+/// Turns `Profile`s into `ResolvedProfile`s, with symbols from its sources.
+#[derive(Default)]
+pub struct Symbolizer {
+    sources: Vec<Box<dyn SymbolSource>>,
+    cache: Option<DiskCache>,
+    memory: Option<Mutex<MemoryCache>>,
+    threads: Option<usize>,
+}
+
+impl Symbolizer {
+    /// Creates a symbolizer without any sources, which resolves nothing but JIT frames.
+    pub fn new() -> Self {
+        Symbolizer::default()
+    }
+
+    /// Creates a symbolizer with the sources the resolve tool uses: debuginfod if DEBUGINFOD_URLS
+    /// is set, then `roots` in order, then /usr/lib/debug, then the symbol servers at `servers`.
+    /// `roots` are also scanned for ELF files with matching build IDs, whatever their names.
+    /// Converted symbols are cached in VIGNETTE_SYMCACHE_DIR, or else in `vignette/symcache` under
+    /// the user's cache directory.
+    ///
+    /// Also returns warnings about sources that are configured but cannot be used, for the caller
+    /// to report.
+    pub fn with_standard_sources(roots: Vec<PathBuf>, servers: Vec<String>) -> (Self, Vec<String>) {
+        let mut symbolizer = Symbolizer::new();
+        let (client, skipped) = debuginfod::Client::from_env();
+        if let Some(client) = client {
+            symbolizer.add_source(client);
+        }
+        let warnings = skipped
+            .into_iter()
+            .map(|url| format!("skipping debuginfod server without an http:// URL: {}", url))
+            .collect();
+        for root in roots {
+            symbolizer.add_source(LocalDir::new(root, true));
+        }
+        symbolizer.add_source(LocalDir::new(PathBuf::from(SYSTEM_DEBUG_DIR), false));
+        if let Some(client) = symbol_server::Client::with_default_cache(servers) {
+            symbolizer.add_source(client);
+        }
+        let cache_dir = match env::var_os("VIGNETTE_SYMCACHE_DIR") {
+            Some(dir) => Some(PathBuf::from(dir)),
+            None => env::var_os("XDG_CACHE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+                .map(|dir| dir.join("vignette").join("symcache")),
+        };
+        if let Some(dir) = cache_dir {
+            symbolizer.set_cache_dir(dir);
+        }
+        (symbolizer, warnings)
+    }
+
+    /// Adds a source to ask for symbols, after the ones added before.
+    pub fn add_source<S: SymbolSource + 'static>(&mut self, source: S) {
+        self.sources.push(Box::new(source));
+    }
+
+    /// Keeps the symbols of modules under `dir` once converted for lookups, so that later
+    /// symbolizers can memory-map them instead of asking the sources again. Converting large
+    /// symbol files takes a while.
+    pub fn set_cache_dir(&mut self, dir: PathBuf) {
+        self.cache = Some(DiskCache::new(dir));
+    }
+
+    /// Creates a symbolizer for a profile of the running process, which reads the symbols of each
+    /// module from the file it was loaded from, or from its separate debug file in /usr/lib/debug.
+    /// Files without DWARF still have the names of their functions in their symbol tables.
+    pub fn for_loaded_modules() -> Self {
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_source(LocalDir::new(PathBuf::from(SYSTEM_DEBUG_DIR), false));
+        symbolizer.add_source(ModuleFiles::new());
+        symbolizer
+    }
+
+    /// Keeps the symbols of up to `modules` modules loaded between calls to `symbolize`, dropping
+    /// the least recently used ones first. This saves services that symbolicate many profiles of
+    /// the same builds from loading the same symbols over and over.
+    pub fn set_memory_cache(&mut self, modules: usize) {
+        self.memory = Some(Mutex::new(MemoryCache {
+            capacity: modules.max(1),
+            clock: 0,
+            symbols: HashMap::new(),
+            loading: HashMap::new(),
+        }));
+    }
+
+    /// Resolves with at most `threads` threads, rather than one per CPU.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = Some(threads.max(1));
+    }
+
+    // Returns the symbols of `module` if they are kept in memory, or else loads them and keeps
+    // them. Errors are added to `problem`.
+    //
+    // If another thread is already loading the same module, this waits for its result instead.
+    // Only that thread reports the errors it runs into.
+    fn load(&self, module: &Module, problem: &mut Problem) -> Option<Arc<ModuleSymbols>> {
+        let memory = match self.memory {
+            Some(ref memory) => memory,
+            None => return self.load_uncached(module, problem).map(Arc::new),
+        };
+        let key = (module.name.clone(), module.debug_id.clone());
+        let loading = {
+            let mut memory = memory.lock().unwrap();
+            if let Some(symbols) = memory.get(module) {
+                return Some(symbols);
+            }
+            if let Some(loading) = memory.loading.get(&key) {
+                let loading = loading.clone();
+                drop(memory);
+                return loading.wait();
+            }
+            let loading = Arc::new(Loading::default());
+            memory.loading.insert(key.clone(), loading.clone());
+            loading
+        };
+
+        // Loaded without holding the lock, so that other modules can be loaded meanwhile. Threads
+        // waiting for the symbols are let go even if loading panics.
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            self.load_uncached(module, problem).map(Arc::new)
+        }));
+        let symbols = result.as_ref().ok().and_then(Clone::clone);
+        {
+            let mut memory = memory.lock().unwrap();
+            memory.loading.remove(&key);
+            if let Some(ref symbols) = symbols {
+                memory.insert(module, symbols.clone());
+            }
+        }
+        loading.finish(symbols);
+        result.unwrap_or_else(|e| panic::resume_unwind(e))
+    }
+
+    // Loads the symbols of `module` from the cache, or else from the first source that has them,
+    // adding them to the cache. Errors are added to `problem`.
+    fn load_uncached(&self, module: &Module, problem: &mut Problem) -> Option<ModuleSymbols> {
+        if let Some(ref cache) = self.cache {
+            match cache.load(module) {
+                Ok(Some(symbols)) => return Some(symbols),
+                Ok(None) => {}
+                Err(e) => problem.errors.push(e),
+            }
+        }
+        for source in &self.sources {
+            match source.find(module) {
+                Ok(Some(symbols)) => {
+                    if let Some(ref cache) = self.cache {
+                        if let Err(e) = cache.store(module, &symbols) {
+                            problem.errors.push(e.into());
+                        }
+                    }
+                    return Some(symbols);
+                }
+                Ok(None) => {}
+                Err(e) => problem.errors.push(e),
+            }
+        }
+        None
+    }
+
+    /// Resolves the frames of `profile`. Modules without symbols do not make this fail, they are
+    /// reported in `Symbolized::problems` instead.
+    ///
+    /// Modules are loaded in parallel, each by a single thread, and dropped once their frames are
+    /// resolved unless kept in memory. The result does not depend on the order the threads finish
+    /// in.
+    pub fn symbolize(&self, profile: Profile) -> Result<Symbolized, Error> {
+        let frame_count = profile.frames.len();
+        let in_range = profile.threads.iter().all(|thread| {
+            thread
+                .samples
+                .iter()
+                .all(|sample| sample.frames.iter().all(|&frame| frame < frame_count))
+        });
+        if !in_range {
+            return Err(Error::Malformed("sample refers to a missing frame"));
+        }
+
+        let modules = &profile.modules;
+        let mut resolved: Vec<Option<ResolvedFrame>> = vec![None; frame_count];
+        let mut by_module: BTreeMap<u32, Vec<usize>> = BTreeMap::new();
+        for (index, frame) in profile.frames.iter().enumerate() {
+            let module = match frame.module_index {
+                Some(module_index) => Some(
+                    modules
+                        .get(module_index as usize)
+                        .ok_or(Error::Malformed("frame refers to a missing module"))?,
+                ),
+                None => None,
+            };
+            match module {
+                Some(&Module {
+                    jit_symbols: None, ..
+                }) => by_module
+                    .entry(frame.module_index.unwrap())
+                    .or_default()
+                    .push(index),
+                // Nothing to load for these.
+                _ => resolved[index] = Some(resolve_frame(frame, module, None)),
+            }
+        }
+
+        let groups: Vec<(u32, Vec<usize>)> = by_module.into_iter().collect();
+        let next_group = AtomicUsize::new(0);
+        let threads = self
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |threads| threads.get()))
+            .min(groups.len());
+        let frames = &profile.frames;
+        let results: Vec<WorkerResult> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut resolved = Vec::new();
+                        let mut problems = Vec::new();
+                        while let Some(&(module_index, ref indices)) =
+                            groups.get(next_group.fetch_add(1, Ordering::SeqCst))
+                        {
+                            let module = &modules[module_index as usize];
+                            let mut problem = Problem {
+                                module_index,
+                                resolved: false,
+                                errors: Vec::new(),
+                            };
+                            let symbols = self.load(module, &mut problem);
+                            problem.resolved = symbols.is_some();
+                            if !problem.resolved || !problem.errors.is_empty() {
+                                problems.push(problem);
+                            }
+                            for &index in indices {
+                                let frame =
+                                    resolve_frame(&frames[index], Some(module), symbols.as_deref());
+                                resolved.push((index, frame));
+                            }
+                        }
+                        (resolved, problems)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        });
+
+        let mut problems = Vec::new();
+        for (frames, worker_problems) in results {
+            for (index, frame) in frames {
+                resolved[index] = Some(frame);
+            }
+            problems.extend(worker_problems);
+        }
+        problems.sort_by_key(|problem| problem.module_index);
+
+        Ok(Symbolized {
+            profile: ResolvedProfile {
+                version: FORMAT_VERSION,
+                modules: profile.modules,
+                diagnostics: profile.diagnostics,
+                threads: profile.threads,
+                frames: resolved
+                    .into_iter()
+                    .map(|frame| frame.expect("every frame resolved"))
+                    .collect(),
+            },
+            problems,
+        })
+    }
+}
+
+// JIT code has no symbol files, the profile carries its symbols instead. They are sorted by
+// address, but may overlap when code was replaced during the session.
+fn lookup_jit_symbol(symbols: &[JitSymbol], ip: u64) -> Option<String> {
+    symbols
+        .iter()
+        .take_while(|symbol| symbol.address <= ip)
+        .filter(|symbol| ip - symbol.address < symbol.size)
+        .last()
+        .map(|symbol| symbol.name.clone())
+}
+
+// Resolves a frame in `module`, given the module's symbols if it has symbol files.
+fn resolve_frame(
+    frame: &Frame,
+    module: Option<&Module>,
+    symbols: Option<&ModuleSymbols>,
+) -> ResolvedFrame {
+    let (name, file) = match module {
+        Some(&Module {
+            jit_symbols: Some(ref jit_symbols),
+            ..
+        }) => (
+            lookup_jit_symbol(jit_symbols, frame.relative_ip)
+                .unwrap_or_else(|| format!("[jit] 0x{:x}", frame.relative_ip)),
+            "[jit]",
+        ),
+        Some(_) => match symbols.and_then(|symbols| symbols.lookup(frame.relative_ip)) {
+            Some(frame) => return frame,
+            None => ("unknown".to_owned(), "unknown"),
+        },
+        // Frames outside any known module only have their absolute IP.
+        None => (format!("[unknown] 0x{:x}", frame.relative_ip), "unknown"),
+    };
+    ResolvedFrame {
+        name,
+        file: file.to_owned(),
+        line: 0,
+        inlined: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use output::{Sample, Thread};
+    use std::{env, process, time::Duration};
+    use threadinfo;
+
+    const DEBUG_ID: &str = "0123456789ABCDEF0123456789ABCDEF0";
+
+    const SYMBOLS: &str = "MODULE Linux x86_64 0123456789ABCDEF0123456789ABCDEF0 libfoo.so
+FILE 0 foo.c
+FUNC 1000 20 0 foo
+1000 10 3 0
+1010 10 4 0
+FUNC 1020 10 0 bar
+1020 10 8 0
+";
+
+    fn module(name: &str, debug_id: &str) -> Module {
+        Module {
+            name: name.to_owned(),
+            code_id: "ABCD".to_owned(),
+            debug_id: debug_id.to_owned(),
+            id_kind: IdKind::BuildId,
+            path: None,
+            base_address: None,
+            size: None,
+            image_vaddr: None,
+            arch: None,
+            soname: None,
+            debug_file: None,
+            jit_symbols: None,
+        }
+    }
+
+    fn frame(module_index: Option<u32>, relative_ip: u64) -> Frame {
+        Frame {
+            module_index,
+            relative_ip,
+            function_rva: None,
+        }
+    }
+
+    // A profile with one sample of all of `frames`.
+    fn profile(modules: Vec<Module>, frames: Vec<Frame>) -> Profile {
+        Profile {
+            version: FORMAT_VERSION,
+            modules,
+            diagnostics: Vec::new(),
+            threads: vec![Thread {
+                thread_id: threadinfo::current_thread().unwrap(),
+                samples: vec![Sample {
+                    frames: (0..frames.len()).collect(),
+                }],
+            }],
+            frames,
+        }
+    }
+
+    fn memory_source() -> MemorySource {
+        let mut source = MemorySource::new();
+        source.insert(DEBUG_ID.to_owned(), SYMBOLS.as_bytes().to_vec());
+        source
+    }
+
+    fn names(profile: &ResolvedProfile) -> Vec<&str> {
+        profile
+            .frames
+            .iter()
+            .map(|frame| frame.name.as_str())
+            .collect()
+    }
+
+    struct FailingSource;
+
+    impl SymbolSource for FailingSource {
+        fn find(&self, _module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+            Err(Error::Io(io::Error::other("unreachable")))
+        }
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let mut jit = module("[jit]", "");
+        jit.jit_symbols = Some(vec![JitSymbol {
+            address: 0x5000,
+            size: 0x10,
+            name: "jitted".to_owned(),
+        }]);
+        let modules = vec![module("libfoo.so", DEBUG_ID), module("libbar.so", "0"), jit];
+        let frames = vec![
+            frame(Some(0), 0x1014),
+            frame(Some(1), 0x10),
+            frame(Some(0), 0x1020),
+            frame(Some(2), 0x5008),
+            frame(None, 0x20),
+        ];
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_source(FailingSource);
+        symbolizer.add_source(memory_source());
+        // More threads than modules.
+        symbolizer.set_threads(4);
+
+        let symbolized = symbolizer.symbolize(profile(modules, frames)).unwrap();
+        assert_eq!(
+            names(&symbolized.profile),
+            vec!["foo", "unknown", "bar", "jitted", "[unknown] 0x20"]
+        );
+        assert_eq!(symbolized.profile.frames[0].file, "foo.c");
+        assert_eq!(symbolized.profile.frames[0].line, 4);
+        assert_eq!(symbolized.profile.threads[0].samples[0].frames.len(), 5);
+
+        // The failing source is reported for both modules, but libfoo.so was found anyway.
+        let problems = &symbolized.problems;
+        assert_eq!(problems.len(), 2);
+        assert_eq!((problems[0].module_index, problems[0].resolved), (0, true));
+        assert_eq!((problems[1].module_index, problems[1].resolved), (1, false));
+        assert_eq!(problems[0].errors.len(), 1);
+    }
+
+    #[test]
+    fn test_symbolize_non_pie() {
+        // A non-PIE executable linked at 0x400000. Its relative IPs are the addresses in the file,
+        // while dump_syms wrote the symbol file's addresses relative to the image.
+        let mut executable = module("libfoo.so", DEBUG_ID);
+        executable.image_vaddr = Some(0x40_0000);
+        let frames = vec![frame(Some(0), 0x40_1004), frame(Some(0), 0x40_1020)];
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_source(memory_source());
+        let symbolized = symbolizer
+            .symbolize(profile(vec![executable.clone()], frames.clone()))
+            .unwrap();
+        assert_eq!(names(&symbolized.profile), vec!["foo", "bar"]);
+
+        // Profiles that don't know the image address are taken to be relative to it already.
+        executable.image_vaddr = None;
+        let symbolized = symbolizer
+            .symbolize(profile(vec![executable], frames))
+            .unwrap();
+        assert_eq!(names(&symbolized.profile), vec!["unknown", "unknown"]);
+    }
+
+    #[test]
+    fn test_symbolize_malformed() {
+        let symbolizer = Symbolizer::new();
+        match symbolizer.symbolize(profile(Vec::new(), vec![frame(Some(0), 0x10)])) {
+            Err(Error::Malformed(_)) => {}
+            result => panic!("unexpected {:?}", result.map(|s| s.problems)),
+        }
+
+        let mut missing_frame = profile(Vec::new(), Vec::new());
+        missing_frame.threads[0].samples[0].frames.push(0);
+        assert!(symbolizer.symbolize(missing_frame).is_err());
+    }
+
+    #[test]
+    fn test_cache_dir() {
+        let dir = env::temp_dir().join(format!("vignette-symcache-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // Symbols without lines, like those from a symbol table.
+        const FUNCTIONS_ONLY_ID: &str = "FEDCBA9876543210FEDCBA9876543210F";
+        let functions_only = "MODULE Linux x86_64 FEDCBA9876543210FEDCBA9876543210F libbar.so
+FUNC 1000 20 0 baz
+";
+        let mut source = memory_source();
+        source.insert(
+            FUNCTIONS_ONLY_ID.to_owned(),
+            functions_only.as_bytes().to_vec(),
+        );
+        let modules = vec![
+            module("libfoo.so", DEBUG_ID),
+            module("libbar.so", FUNCTIONS_ONLY_ID),
+        ];
+        let frames = vec![frame(Some(0), 0x1004), frame(Some(1), 0x1004)];
+
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_source(source);
+        symbolizer.set_cache_dir(dir.clone());
+        let symbolized = symbolizer
+            .symbolize(profile(modules.clone(), frames.clone()))
+            .unwrap();
+        assert_eq!(names(&symbolized.profile), vec!["foo", "baz"]);
+
+        // Found in the cache, without any sources. Symbols without lines are not kept, so that
+        // sources are asked again in case they have better ones by now.
+        let mut cached = Symbolizer::new();
+        cached.set_cache_dir(dir.clone());
+        let symbolized = cached.symbolize(profile(modules, frames)).unwrap();
+        assert_eq!(names(&symbolized.profile), vec!["foo", "unknown"]);
+        assert_eq!(symbolized.problems.len(), 1);
+        assert!(!symbolized.problems[0].resolved);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Counts the modules asked for.
+    struct CountingSource(MemorySource, Arc<AtomicUsize>);
+
+    impl SymbolSource for CountingSource {
+        fn find(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            self.0.find(module)
+        }
+    }
+
+    #[test]
+    fn test_memory_cache() {
+        const OTHER_DEBUG_ID: &str = "FEDCBA9876543210FEDCBA9876543210F";
+        let mut source = memory_source();
+        source.insert(OTHER_DEBUG_ID.to_owned(), SYMBOLS.as_bytes().to_vec());
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_source(CountingSource(source, count.clone()));
+        symbolizer.set_memory_cache(1);
+
+        let symbolize = |name: &str, debug_id: &str| {
+            let profile = profile(vec![module(name, debug_id)], vec![frame(Some(0), 0x1004)]);
+            let symbolized = symbolizer.symbolize(profile).unwrap();
+            assert_eq!(names(&symbolized.profile), vec!["foo"]);
+        };
+        symbolize("libfoo.so", DEBUG_ID);
+        symbolize("libfoo.so", DEBUG_ID);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        // Only one module is kept, so loading another drops the first.
+        symbolize("libbar.so", OTHER_DEBUG_ID);
+        symbolize("libfoo.so", DEBUG_ID);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    // Takes a while to find anything, so that threads asking for the same module overlap.
+    struct SlowSource(CountingSource);
+
+    impl SymbolSource for SlowSource {
+        fn find(&self, module: &Module) -> Result<Option<ModuleSymbols>, Error> {
+            thread::sleep(Duration::from_millis(200));
+            self.0.find(module)
+        }
+    }
+
+    #[test]
+    fn test_memory_cache_concurrent() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_source(SlowSource(CountingSource(memory_source(), count.clone())));
+        symbolizer.set_memory_cache(1);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let modules = vec![module("libfoo.so", DEBUG_ID)];
+                    let profile = profile(modules, vec![frame(Some(0), 0x1004)]);
+                    let symbolized = symbolizer.symbolize(profile).unwrap();
+                    assert_eq!(names(&symbolized.profile), vec!["foo"]);
+                });
+            }
+        });
+        // Loaded by one thread, and handed to the others.
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_parse_mismatch() {
+        // This test binary is an ELF file, but not libfoo.so.
+        let path = env::current_exe().unwrap();
+        match parse_file(&path, &module("libfoo.so", DEBUG_ID)) {
+            Err(Error::Mismatch(_)) => {}
+            Err(e) => panic!("unexpected {}", e),
+            Ok(_) => panic!("matched"),
+        }
+        assert!(ModuleSymbols::parse(b"garbage", "test", &module("libfoo.so", DEBUG_ID)).is_err());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
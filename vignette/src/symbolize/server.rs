@@ -0,0 +1,536 @@
+// A symbolication service over HTTP, so that many profiles can be resolved by one process that
+// keeps the symbols of frequently seen modules loaded.
+//
+// Two endpoints take POST requests:
+//
+// - `/profile` takes an unresolved profile as written by `output::save`, of any version and
+//   compression, and returns the `ResolvedProfile` as JSON.
+// - `/symbolicate/v5` takes bare module offsets in the shape of Mozilla's symbolication API,
+//   `{"jobs": [{"memoryMap": [[name, debug ID, code ID]], "stacks": [[[module index, offset]]]}]}`,
+//   or a single job without the `jobs` wrapper. The code ID is optional, and module index -1
+//   stands for an address outside any module.
+
+extern crate serde;
+extern crate serde_json;
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    panic,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{Symbolized, Symbolizer};
+use http::{self, Request};
+use output::{self, Frame, IdKind, Module, Profile, ResolvedFrame, FORMAT_VERSION};
+
+// Largest request body accepted. Profiles of long sessions are large, but not this large.
+const MAX_BODY: u64 = 64 << 20;
+
+// Largest profile accepted once decompressed, which compresses well enough that MAX_BODY alone
+// would not stop it from taking gigabytes.
+const MAX_PROFILE_SIZE: u64 = 256 << 20;
+
+// Connections served at once. Each takes memory in proportion to MAX_BODY and MAX_PROFILE_SIZE,
+// plus the symbols of the modules it needs, so this bounds what requests take besides the memory
+// cache.
+const WORKERS: usize = 8;
+
+// How long a connection may take to send its whole request, and to receive the response. Workers
+// are few, so clients that send or read slowly must not keep them.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Job {
+    // Name, debug ID and optionally code ID of each module.
+    memory_map: Vec<Vec<String>>,
+    // Module index and module offset of each frame, innermost first.
+    stacks: Vec<Vec<(i64, u64)>>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SymbolicateRequest {
+    Jobs { jobs: Vec<Job> },
+    Job(Job),
+}
+
+#[derive(Serialize)]
+struct SymbolicateResponse {
+    results: Vec<JobResult>,
+}
+
+#[derive(Serialize)]
+struct JobResult {
+    stacks: Vec<Vec<StackFrame>>,
+    // Whether symbols were found for each module that frames refer to, by "name/debug ID".
+    found_modules: BTreeMap<String, bool>,
+}
+
+#[derive(Serialize)]
+struct StackFrame {
+    // Index of the frame in its stack.
+    frame: usize,
+    module_offset: String,
+    // The remaining fields are left out for frames outside any module, and the function, file and
+    // line for frames in modules without symbols.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    module: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    function: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    inlines: Vec<Inline>,
+}
+
+#[derive(Serialize)]
+struct Inline {
+    function: String,
+    file: String,
+    line: u32,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+// A response's status, content type and body.
+type Response = (u16, &'static str, Vec<u8>);
+
+fn json_response<T: serde::Serialize>(value: &T) -> Response {
+    (
+        200,
+        "application/json",
+        serde_json::to_vec(value).expect("serializable response"),
+    )
+}
+
+fn error_response(status: u16, error: String) -> Response {
+    let body = serde_json::to_vec(&ErrorResponse { error }).expect("serializable error");
+    (status, "application/json", body)
+}
+
+// The kind of a code ID the client sent. Build IDs can be any length, so hex code IDs are taken
+// for them; a text hash taken for one only costs a lookup that finds nothing.
+fn code_id_kind(code_id: &str) -> IdKind {
+    if !code_id.is_empty() && code_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        IdKind::BuildId
+    } else {
+        IdKind::TextHash
+    }
+}
+
+// Turns a job into a profile without samples, with one frame per stack frame in order.
+fn job_profile(job: &Job) -> Result<Profile, String> {
+    let modules = job
+        .memory_map
+        .iter()
+        .map(|entry| {
+            let (name, debug_id, code_id) = match entry.len() {
+                2 => (&entry[0], &entry[1], None),
+                3 => (&entry[0], &entry[1], Some(&entry[2])),
+                _ => return Err("memory map entries are [name, debug ID, code ID]".to_owned()),
+            };
+            Ok(Module {
+                name: name.clone(),
+                code_id: code_id.cloned().unwrap_or_default().to_uppercase(),
+                debug_id: debug_id.to_uppercase(),
+                id_kind: code_id.map_or(IdKind::TextHash, |code_id| code_id_kind(code_id)),
+                path: None,
+                base_address: None,
+                size: None,
+                image_vaddr: None,
+                arch: None,
+                soname: None,
+                debug_file: None,
+                jit_symbols: None,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut frames = Vec::new();
+    for &(module_index, offset) in job.stacks.iter().flatten() {
+        let module_index = match module_index {
+            -1 => None,
+            index if index >= 0 && (index as usize) < modules.len() => Some(index as u32),
+            index => return Err(format!("frame refers to missing module {}", index)),
+        };
+        frames.push(Frame {
+            module_index,
+            relative_ip: offset,
+            function_rva: None,
+        });
+    }
+    Ok(Profile {
+        version: FORMAT_VERSION,
+        modules,
+        diagnostics: Vec::new(),
+        threads: Vec::new(),
+        frames,
+    })
+}
+
+// Splits the resolved frames of `job` back into its stacks.
+fn job_result(job: &Job, symbolized: &Symbolized) -> JobResult {
+    let modules = &symbolized.profile.modules;
+    let found: Vec<bool> = (0..modules.len())
+        .map(|index| {
+            !symbolized
+                .problems
+                .iter()
+                .any(|problem| problem.module_index as usize == index && !problem.resolved)
+        })
+        .collect();
+    let mut frames = symbolized.profile.frames.iter();
+    let stacks = job
+        .stacks
+        .iter()
+        .map(|stack| {
+            stack
+                .iter()
+                .zip(&mut frames)
+                .enumerate()
+                .map(|(index, (&(module_index, offset), resolved))| {
+                    let mut frame = StackFrame {
+                        frame: index,
+                        module_offset: format!("0x{:x}", offset),
+                        module: None,
+                        function: None,
+                        file: None,
+                        line: None,
+                        inlines: Vec::new(),
+                    };
+                    if module_index >= 0 {
+                        let module_index = module_index as usize;
+                        frame.module = Some(modules[module_index].name.clone());
+                        if found[module_index] {
+                            fill_frame(&mut frame, resolved);
+                        }
+                    }
+                    frame
+                })
+                .collect()
+        })
+        .collect();
+    // Modules without frames were not looked for.
+    let found_modules = job
+        .stacks
+        .iter()
+        .flatten()
+        .filter(|&&(module_index, _)| module_index >= 0)
+        .map(|&(module_index, _)| {
+            let module = &modules[module_index as usize];
+            let key = format!("{}/{}", module.name, module.debug_id);
+            (key, found[module_index as usize])
+        })
+        .collect();
+    JobResult {
+        stacks,
+        found_modules,
+    }
+}
+
+fn fill_frame(frame: &mut StackFrame, resolved: &ResolvedFrame) {
+    frame.function = Some(resolved.name.clone());
+    frame.file = Some(resolved.file.clone());
+    frame.line = Some(resolved.line);
+    frame.inlines = resolved
+        .inlined
+        .iter()
+        .map(|inlined| Inline {
+            function: inlined.name.clone(),
+            file: inlined.file.clone(),
+            line: inlined.line,
+        })
+        .collect();
+}
+
+fn symbolicate(symbolizer: &Symbolizer, body: &[u8]) -> Response {
+    let jobs = match serde_json::from_slice(body) {
+        Ok(SymbolicateRequest::Jobs { jobs }) => jobs,
+        Ok(SymbolicateRequest::Job(job)) => vec![job],
+        Err(e) => return error_response(400, e.to_string()),
+    };
+    let mut results = Vec::new();
+    for job in &jobs {
+        let profile = match job_profile(job) {
+            Ok(profile) => profile,
+            Err(e) => return error_response(400, e),
+        };
+        match symbolizer.symbolize(profile) {
+            Ok(symbolized) => results.push(job_result(job, &symbolized)),
+            Err(e) => return error_response(400, e.to_string()),
+        }
+    }
+    json_response(&SymbolicateResponse { results })
+}
+
+fn resolve_profile(symbolizer: &Symbolizer, body: &[u8]) -> Response {
+    let profile = match output::load_limited(body, MAX_PROFILE_SIZE) {
+        Ok(profile) => profile,
+        Err(e) => return error_response(400, e.to_string()),
+    };
+    match symbolizer.symbolize(profile) {
+        Ok(symbolized) => json_response(&symbolized.profile),
+        Err(e) => error_response(400, e.to_string()),
+    }
+}
+
+fn handle(symbolizer: &Symbolizer, request: &Request) -> Response {
+    let endpoint: fn(&Symbolizer, &[u8]) -> Response = match &request.path[..] {
+        "/profile" => resolve_profile,
+        "/symbolicate/v5" => symbolicate,
+        path => return error_response(404, format!("no endpoint at {}", path)),
+    };
+    if request.method != "POST" {
+        return error_response(405, format!("{} takes POST requests", request.path));
+    }
+    endpoint(symbolizer, &request.body)
+}
+
+// A connection that fails reads and writes once `deadline` has passed, however much each of them
+// transferred. Socket timeouts alone only limit how long a single read or write may take.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl<'a> Deadline<'a> {
+    fn new(stream: &'a TcpStream, timeout: Duration) -> Self {
+        Deadline {
+            stream,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    fn remaining(&self) -> io::Result<Duration> {
+        match self.deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if remaining > Duration::ZERO => Ok(remaining),
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut, "deadline passed")),
+        }
+    }
+}
+
+impl<'a> Read for Deadline<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.remaining()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl<'a> Write for Deadline<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.remaining()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn serve_connection(symbolizer: &Symbolizer, stream: TcpStream) {
+    let reader = BufReader::new(Deadline::new(&stream, READ_TIMEOUT));
+    let (status, content_type, body) = match http::read_request(reader, MAX_BODY) {
+        Ok(request) => handle(symbolizer, &request),
+        Err(e) => error_response(400, e.to_string()),
+    };
+    // The client is gone if this fails, so there is no one to report errors to.
+    let writer = Deadline::new(&stream, WRITE_TIMEOUT);
+    let _ = http::write_response(writer, status, content_type, &body);
+}
+
+/// Answers symbolication requests on `listener` until accepting connections fails, with a fixed
+/// number of worker threads. Connections wait in the listen backlog while all of them are busy.
+/// `symbolizer` is shared by all of them, so give it a memory cache with
+/// `Symbolizer::set_memory_cache` to keep symbols loaded between requests.
+pub fn serve(listener: TcpListener, symbolizer: Arc<Symbolizer>) -> io::Result<()> {
+    // Without a buffer, connections are only accepted once a worker is free to take them.
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(0);
+    let receiver = Mutex::new(receiver);
+    thread::scope(|scope| {
+        for _ in 0..WORKERS {
+            scope.spawn(|| loop {
+                let stream = match receiver.lock().unwrap().recv() {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                // A request that panics only loses its own connection.
+                let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                    serve_connection(&symbolizer, stream)
+                }));
+            });
+        }
+        let error = loop {
+            match listener.accept() {
+                Ok((stream, _)) => sender.send(stream).expect("workers are running"),
+                Err(e) => break e,
+            }
+        };
+        // Lets the workers finish once they are done with their connections.
+        drop(sender);
+        Err(error)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::MemorySource;
+    use super::*;
+    use output::{Sample, Thread};
+    use std::time::Duration;
+    use threadinfo;
+
+    const SYMBOLS: &str = "MODULE Linux x86_64 0123456789ABCDEF0123456789ABCDEF0 libfoo.so
+FILE 0 foo.c
+FUNC 1000 20 0 foo
+1000 20 3 0
+";
+
+    fn symbolizer() -> Symbolizer {
+        let mut source = MemorySource::new();
+        source.insert(
+            "0123456789ABCDEF0123456789ABCDEF0".to_owned(),
+            SYMBOLS.as_bytes().to_vec(),
+        );
+        let mut symbolizer = Symbolizer::new();
+        symbolizer.add_source(source);
+        symbolizer.set_memory_cache(4);
+        symbolizer
+    }
+
+    fn post(path: &str, body: &[u8]) -> Request {
+        Request {
+            method: "POST".to_owned(),
+            path: path.to_owned(),
+            body: body.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_symbolicate_v5() {
+        let symbolizer = symbolizer();
+        let body = br#"{"jobs": [{
+            "memoryMap": [
+                ["libfoo.so", "0123456789abcdef0123456789abcdef0"],
+                ["libbar.so", "AAAA", "BBBB"]
+            ],
+            "stacks": [[[0, 4100], [1, 16], [-1, 255]]]
+        }]}"#;
+        let (status, _, response) = handle(&symbolizer, &post("/symbolicate/v5", body));
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        let result = &response["results"][0];
+        let stack = &result["stacks"][0];
+        assert_eq!(stack[0]["function"], "foo");
+        assert_eq!(stack[0]["file"], "foo.c");
+        assert_eq!(stack[0]["line"], 3);
+        assert_eq!(stack[0]["module_offset"], "0x1004");
+        assert_eq!(stack[1]["frame"], 1);
+        assert_eq!(stack[1]["module"], "libbar.so");
+        assert!(stack[1].get("function").is_none());
+        assert!(stack[2].get("module").is_none());
+        assert_eq!(
+            result["found_modules"]["libfoo.so/0123456789ABCDEF0123456789ABCDEF0"],
+            true
+        );
+        assert_eq!(result["found_modules"]["libbar.so/AAAA"], false);
+
+        // A single job may come without the wrapper, and the symbols are still loaded.
+        let body = br#"{"memoryMap": [["libfoo.so", "0123456789ABCDEF0123456789ABCDEF0"]],
+                        "stacks": [[[0, 4096]]]}"#;
+        let (status, _, response) = handle(&symbolizer, &post("/symbolicate/v5", body));
+        assert_eq!(status, 200);
+        let response: serde_json::Value = serde_json::from_slice(&response).unwrap();
+        assert_eq!(response["results"][0]["stacks"][0][0]["function"], "foo");
+    }
+
+    #[test]
+    fn test_code_id_kind() {
+        let build_id = "BFAA2814D2DB2EA508B6D967319FB6FEF5B14C2C";
+        assert_eq!(code_id_kind(build_id), IdKind::BuildId);
+        // Short build IDs, like those of 128-bit hashes.
+        assert_eq!(code_id_kind(&build_id[..32]), IdKind::BuildId);
+        assert_eq!(code_id_kind(&build_id[..16]), IdKind::BuildId);
+        assert_eq!(code_id_kind(&format!("{}XYZ", build_id)), IdKind::TextHash);
+    }
+
+    #[test]
+    fn test_profile() {
+        let mut profile = job_profile(&Job {
+            memory_map: vec![vec![
+                "libfoo.so".to_owned(),
+                "0123456789ABCDEF0123456789ABCDEF0".to_owned(),
+            ]],
+            stacks: vec![vec![(0, 0x1004)]],
+        })
+        .unwrap();
+        profile.threads.push(Thread {
+            thread_id: threadinfo::current_thread().unwrap(),
+            samples: vec![Sample { frames: vec![0] }],
+        });
+        let mut body = Vec::new();
+        output::save(&profile, &mut body, output::compression::Compression::None).unwrap();
+        let (status, _, response) = handle(&symbolizer(), &post("/profile", &body));
+        assert_eq!(status, 200);
+        let resolved = output::load_resolved(&response[..]).unwrap();
+        assert_eq!(resolved.frames[0].name, "foo");
+        assert_eq!(resolved.threads[0].samples[0].frames, vec![0]);
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let symbolizer = symbolizer();
+        let status = |request: Request| handle(&symbolizer, &request).0;
+        assert_eq!(status(post("/symbolicate/v5", b"{")), 400);
+        assert_eq!(status(post("/profile", b"[]")), 400);
+        let missing_module = br#"{"memoryMap": [], "stacks": [[[0, 1]]]}"#;
+        assert_eq!(status(post("/symbolicate/v5", missing_module)), 400);
+        assert_eq!(status(post("/elsewhere", b"")), 404);
+        let get = Request {
+            method: "GET".to_owned(),
+            ..post("/profile", b"")
+        };
+        assert_eq!(status(get), 405);
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/symbolicate/v5", listener.local_addr().unwrap());
+        let symbolizer = Arc::new(symbolizer());
+        thread::spawn(move || serve(listener, symbolizer));
+        let status = http::get(&url, Duration::from_secs(5), 1 << 20, &mut Vec::new()).unwrap();
+        assert_eq!(status, 405);
+    }
+
+    #[test]
+    fn test_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        (&client).write_all(b"x").unwrap();
+
+        let mut reader = Deadline::new(&stream, Duration::from_secs(5));
+        assert_eq!(reader.read(&mut [0; 4]).unwrap(), 1);
+        // A client that sends nothing more only keeps the connection until the deadline.
+        let mut reader = Deadline::new(&stream, Duration::from_millis(50));
+        let error = reader.read(&mut [0; 4]).unwrap_err();
+        assert!(matches!(
+            error.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ));
+        assert_eq!(
+            reader.read(&mut [0; 4]).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+}
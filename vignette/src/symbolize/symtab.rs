@@ -0,0 +1,89 @@
+// Function names from the symbol tables of ELF files without DWARF, which symbolic only reads for
+// Mach-O files. They are turned into the PUBLIC records of a breakpad symbol file, so that they
+// end up in a symcache like any other symbols.
+
+extern crate goblin;
+extern crate rustc_demangle;
+
+use std::fmt::Write;
+
+use self::goblin::{
+    elf::{
+        section_header::SHN_UNDEF,
+        sym::{Symtab, STT_FUNC},
+        Elf,
+    },
+    strtab::Strtab,
+};
+use self::rustc_demangle::demangle;
+
+// Adds the addresses and names of the functions defined in `symtab` to `functions`.
+fn functions(symtab: &Symtab, strtab: &Strtab, functions: &mut Vec<(u64, String)>) {
+    for sym in symtab.iter() {
+        if sym.st_type() != STT_FUNC || sym.st_shndx == SHN_UNDEF as usize || sym.st_value == 0 {
+            continue;
+        }
+        if let Some(Ok(name)) = strtab.get(sym.st_name) {
+            if !name.is_empty() {
+                functions.push((sym.st_value, name.to_owned()));
+            }
+        }
+    }
+}
+
+/// Returns the functions in the `.symtab` of the ELF file `contents`, or in its `.dynsym` if it
+/// was stripped, as a breakpad symbol file for the module `name` built for `arch`. Addresses are
+/// made relative to `vmaddr`. None if the file has neither.
+pub fn breakpad_symbols(contents: &[u8], name: &str, arch: &str, vmaddr: u64) -> Option<String> {
+    let elf = Elf::parse(contents).ok()?;
+    let mut symbols = Vec::new();
+    functions(&elf.syms, &elf.strtab, &mut symbols);
+    if symbols.is_empty() {
+        functions(&elf.dynsyms, &elf.dynstrtab, &mut symbols);
+    }
+    if symbols.is_empty() {
+        return None;
+    }
+    // Aliases share an address. Keep one of them, whichever sorts first for stable output.
+    symbols.sort();
+    symbols.dedup_by_key(|&mut (address, _)| address);
+
+    // The module's identity was already checked, and does not matter past here.
+    let mut breakpad = format!(
+        "MODULE Linux {} 000000000000000000000000000000000 {}\n",
+        arch, name
+    );
+    for (address, symbol) in symbols {
+        if address < vmaddr {
+            continue;
+        }
+        // Rust symbols without their hash, others as they are.
+        writeln!(
+            breakpad,
+            "PUBLIC {:x} 0 {:#}",
+            address - vmaddr,
+            demangle(&symbol)
+        )
+        .expect("writing to a String");
+    }
+    Some(breakpad)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn test_breakpad_symbols() {
+        // This test binary has a symbol table.
+        let contents = fs::read(env::current_exe().unwrap()).unwrap();
+        let symbols = breakpad_symbols(&contents, "test", "x86_64", 0).unwrap();
+        assert!(symbols.starts_with("MODULE Linux x86_64 "));
+        assert!(symbols
+            .lines()
+            .any(|line| line.starts_with("PUBLIC ") && line.contains("test_breakpad_symbols")));
+
+        assert!(breakpad_symbols(b"garbage", "test", "x86_64", 0).is_none());
+    }
+}